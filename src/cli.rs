@@ -0,0 +1,171 @@
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use elba::package::Name as PackageName;
+use log::{error, info};
+use semver::Version;
+use structopt::StructOpt;
+
+use crate::config::{ListenMode, CONFIG};
+use crate::controller::{normalize_group, Controller};
+use crate::database::Database;
+use crate::error::{Error, Result};
+use crate::github::{Github, GithubApi};
+use crate::{fsck, rebuild, verify};
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "A bot maintaining the elba package index")]
+pub struct Cli {
+    #[structopt(subcommand)]
+    pub subcommand: Option<Subcommand>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Subcommand {
+    /// Execute commands from issue comments, which is the default
+    Serve,
+    /// Publish the package in a local directory on behalf of a Github user
+    PublishLocal {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Github user name of the publisher
+        #[structopt(long)]
+        publisher: String,
+    },
+    /// Yank a package version, or unyank it with `--undo`, waiting for the
+    /// pull request in pull request mode
+    Yank {
+        name: PackageName,
+        version: Version,
+        #[structopt(long)]
+        undo: bool,
+    },
+    /// Render the package list into the index README, which is proposed by a
+    /// pull request in pull request mode
+    RegenReadme,
+    /// List packages in database
+    ListPackages {
+        /// Only list packages in the namespace
+        #[structopt(parse(try_from_str = normalize_group))]
+        group: Option<String>,
+    },
+    /// Make a Github user the only owner of a namespace
+    SetOwner {
+        #[structopt(parse(try_from_str = normalize_group))]
+        group: String,
+        user: String,
+    },
+    /// Check the consistency between index, store and database
+    Fsck {
+        /// Repair the database and README to follow the index, leaving the
+        /// README to `regen-readme` in pull request mode
+        #[structopt(long)]
+        repair: bool,
+    },
+    /// Rebuild the database from index and store
+    RebuildDb {
+        /// Github user name of the publisher of packages whose publisher is
+        /// not recorded, e.g. those published before it was
+        #[structopt(long)]
+        assume_publisher: Option<String>,
+    },
+    /// Check that every commit changing metafiles in the index is signed by the bot
+    VerifySignatures {
+        /// The SSH public key or the OpenPGP certificate of the bot
+        #[structopt(parse(from_os_str))]
+        public_key: PathBuf,
+        /// Only check the commits after this one
+        #[structopt(long)]
+        since: Option<String>,
+    },
+}
+
+pub async fn run(subcommand: Subcommand) -> Result<()> {
+    match subcommand {
+        Subcommand::Serve => serve().await,
+        Subcommand::PublishLocal { path, publisher } => {
+            let controller = Controller::new().await?;
+            let publisher = controller.query_user(&publisher).await?;
+            controller.publish_local(path, publisher).await
+        }
+        Subcommand::Yank {
+            name,
+            version,
+            undo,
+        } => {
+            let controller = Controller::new().await?;
+            controller.yank_package(&name, &version, !undo).await
+        }
+        Subcommand::RegenReadme => Controller::new().await?.regen_readme().await,
+        Subcommand::ListPackages { group } => {
+            let database = Database::open(&CONFIG.db_path)?;
+            let mut packages = database.query_package(group.as_deref())?;
+            packages.sort_by(|a, b| {
+                (&a.group, &a.name, &a.version).cmp(&(&b.group, &b.name, &b.version))
+            });
+            for package in packages {
+                let user_name = database.query_user(package.user_id)?.unwrap().name;
+                println!(
+                    "{}/{} {} @{}{}",
+                    package.group,
+                    package.name,
+                    package.version,
+                    user_name,
+                    if package.yanked { " (yanked)" } else { "" }
+                );
+            }
+            Ok(())
+        }
+        Subcommand::SetOwner { group, user } => {
+            let controller = Controller::new().await?;
+            controller.set_owner(&group, &user).await
+        }
+        Subcommand::Fsck { repair } => tokio::task::block_in_place(|| fsck::run(repair)),
+        Subcommand::RebuildDb { assume_publisher } => {
+            let assume_publisher = match assume_publisher {
+                Some(user_name) => Some(Github::new().await?.query_user(&user_name).await?),
+                None => None,
+            };
+            tokio::task::block_in_place(|| rebuild::run(assume_publisher))
+        }
+        Subcommand::VerifySignatures { public_key, since } => {
+            tokio::task::block_in_place(|| verify::run(&public_key, since.as_deref()))
+        }
+    }
+}
+
+/// Resume the jobs of the last run, then run the controller forever, running
+/// it again on failure
+///
+/// The controller is kept across the runs, as the jobs it has spawned keep
+/// running. The webhook listener is bound once as well, so that the port is not
+/// lost to another process meanwhile.
+async fn serve() -> Result<()> {
+    let listener = match CONFIG.listen_mode {
+        ListenMode::Poll => None,
+        ListenMode::Webhook => {
+            let addr = CONFIG
+                .webhook_addr
+                .ok_or(Error::MissingConfig("WEBHOOK_ADDR"))?;
+            let listener = TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            Some(listener)
+        }
+    };
+
+    let controller = Arc::new(Controller::new().await?);
+    controller.resume_jobs().await?;
+
+    loop {
+        info!("Controller started");
+        let listener = listener.as_ref().map(TcpListener::try_clone).transpose()?;
+        let res = tokio::spawn(controller.clone().run(listener)).await?;
+
+        if let Err(err) = res {
+            error!("Controller failure: {}", err);
+            tokio::time::delay_for(Duration::from_secs(5)).await;
+        }
+    }
+}
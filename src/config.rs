@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use failure::ResultExt as _;
@@ -14,19 +15,182 @@ lazy_static! {
 pub struct Config {
     pub db_path: PathBuf,
     pub bot_name: String,
-    pub bot_email: String,
-    pub bot_pwd: String,
-    pub access_token: String,
-    pub store_repo_name: String,
+    /// The email of the bot user, which `github` git auth pushes with unless
+    /// the bot is a Github App
+    pub bot_email: Option<String>,
+    pub bot_pwd: Option<String>,
+    pub access_token: Option<String>,
+    pub github_app_id: Option<u64>,
+    pub github_app_installation_id: Option<u64>,
+    pub github_app_private_key: Option<PathBuf>,
     pub index_repo_name: String,
-    pub index_issue_number: String,
+    pub index_repo_url: Option<String>,
+    pub index_issue_number: Option<i64>,
+    /// Accept commands on all issues with the label besides the index issue
+    pub index_issue_label: Option<String>,
     pub index_checkout: PathBuf,
-    pub store_checkout: PathBuf,
+    #[serde(default)]
+    pub index_git_auth: GitAuth,
+    /// The branch of the index, or the default branch of the remote if unset
+    pub index_branch: Option<String>,
+    #[serde(default)]
+    pub index_publish_mode: IndexPublishMode,
+    /// Merge publish pull requests once checks pass, instead of waiting for a
+    /// maintainer to approve them
+    #[serde(default)]
+    pub index_auto_merge: bool,
+    #[serde(default)]
+    pub store_backend: StoreBackend,
     pub store_max_size: u64,
+    pub store_repo_name: Option<String>,
+    pub store_repo_url: Option<String>,
+    pub store_checkout: Option<PathBuf>,
+    #[serde(default)]
+    pub store_git_auth: GitAuth,
+    /// The branch of the store, or the default branch of the remote if unset
+    pub store_branch: Option<String>,
+    pub store_local_dir: Option<PathBuf>,
+    pub store_url_prefix: Option<String>,
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_region: Option<String>,
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
+    /// The token for repositories with `token` git auth
+    pub git_token: Option<String>,
+    /// The private key for repositories with `ssh-key` git auth
+    pub git_ssh_key: Option<PathBuf>,
+    pub git_ssh_key_passphrase: Option<String>,
+    #[serde(default)]
+    pub listen_mode: ListenMode,
+    pub webhook_addr: Option<SocketAddr>,
+    pub webhook_secret: Option<String>,
+    #[serde(default)]
+    pub report_mode: ReportMode,
+    /// Sign the commits to the index and store repositories
+    pub commit_signing: Option<SigningFormat>,
+    /// An armored OpenPGP secret key, or an SSH private key without passphrase
+    pub commit_signing_key: Option<PathBuf>,
+    /// Commands older than this are not executed when catching up after downtime
+    #[serde(default = "default_command_max_age_hours")]
+    pub command_max_age_hours: i64,
+}
+
+fn default_command_max_age_hours() -> i64 {
+    24
+}
+
+/// How the bot receives new issue comments
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenMode {
+    /// Poll the issue comments endpoint
+    Poll,
+    /// Receive `issue_comment` deliveries from a Github webhook
+    Webhook,
+}
+
+impl Default for ListenMode {
+    fn default() -> Self {
+        ListenMode::Poll
+    }
+}
+
+/// How the bot reports the progress of a command
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportMode {
+    /// Append the report to the comment of the command, which requires admin
+    /// rights on the index repository
+    Edit,
+    /// Reply with a comment of the bot quoting the command, and keep editing it
+    Reply,
+}
+
+impl Default for ReportMode {
+    fn default() -> Self {
+        ReportMode::Edit
+    }
+}
+
+/// How the entries of published packages get into the index
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndexPublishMode {
+    /// Commit to the index branch directly
+    Push,
+    /// Push a branch per publish and merge it by a pull request, for indexes
+    /// with protected branches
+    PullRequest,
+}
+
+impl Default for IndexPublishMode {
+    fn default() -> Self {
+        IndexPublishMode::Push
+    }
+}
+
+/// How to authenticate to the remote of a repository
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GitAuth {
+    /// Push over HTTPS as the bot, with the installation token of the Github
+    /// App or `BOT_EMAIL` and `BOT_PWD`
+    Github,
+    /// Authenticate over HTTPS with `GIT_TOKEN`
+    Token,
+    /// Authenticate over SSH with the private key `GIT_SSH_KEY`
+    SshKey,
+    /// Authenticate over SSH with the keys in the SSH agent
+    SshAgent,
+    /// Never authenticate, e.g. for repositories of packages to publish
+    Anonymous,
+}
+
+impl Default for GitAuth {
+    fn default() -> Self {
+        GitAuth::Github
+    }
+}
+
+/// The kind of signatures on commits
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SigningFormat {
+    /// Sign with `gpg`
+    Openpgp,
+    /// Sign with `ssh-keygen`, which requires OpenSSH 8.1 or later
+    Ssh,
+}
+
+/// Where package tarballs are stored
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    /// Commit tarballs into the Github repository `STORE_REPO_NAME`
+    Git,
+    /// Copy tarballs into `STORE_LOCAL_DIR`, which is served at `STORE_URL_PREFIX`
+    Local,
+    /// Put tarballs into an S3 compatible object store
+    S3,
+}
+
+impl Default for StoreBackend {
+    fn default() -> Self {
+        StoreBackend::Git
+    }
 }
 
 impl Config {
     fn from_env() -> Result<Self> {
         Ok(envy::from_env().context("while reading from environment")?)
     }
+
+    /// The email to commit as, which defaults to the noreply address of the
+    /// bot user
+    pub fn commit_email(&self) -> String {
+        self.bot_email
+            .clone()
+            .unwrap_or_else(|| format!("{}@users.noreply.github.com", self.bot_name))
+    }
 }
@@ -1,12 +1,36 @@
+use elba::package::Name as PackageName;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
 use crate::config::CONFIG;
 use crate::error::Result;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Command {
     Publish {
         git: String,
         refname: Option<String>,
+        /// Directory of the package in the repository, if not at the root
+        path: Option<String>,
+    },
+    Yank {
+        name: PackageName,
+        version: Version,
+        yanked: bool,
+    },
+    Owner {
+        group: String,
+        user: String,
+        add: bool,
+    },
+    TransferNamespace {
+        group: String,
+        user: String,
+    },
+    AcceptTransfer {
+        group: String,
     },
+    Status,
 }
 
 impl Command {
@@ -18,8 +42,15 @@ impl Command {
 }
 
 mod parse {
-    use super::Command;
-    use nom::{bytes::complete::*, character::complete::*, combinator::opt, IResult};
+    use super::{Command, PackageName, Version};
+    use crate::controller::normalize_group;
+    use nom::{
+        branch::alt,
+        bytes::complete::*,
+        character::complete::*,
+        combinator::{cut, map_res, not, opt, value},
+        IResult,
+    };
 
     pub fn parse_command<'a>(i: &'a str, bot_name: &'a str) -> IResult<&'a str, Option<Command>> {
         let (i, _) = multispace0(i)?;
@@ -30,7 +61,14 @@ mod parse {
         }
 
         let (i, _) = multispace1(i)?;
-        let (i, command) = parse_publish(i)?;
+        let (i, command) = alt((
+            parse_publish,
+            parse_yank,
+            parse_owner,
+            parse_transfer,
+            parse_accept_transfer,
+            parse_status,
+        ))(i)?;
 
         Ok((i, Some(command)))
     }
@@ -50,18 +88,105 @@ mod parse {
         let (i, git) = word(i)?;
         let (i, refname) = opt(|i| {
             let (i, _) = multispace1(i)?;
+            let (i, _) = not(tag("--"))(i)?;
             word(i)
         })(i)?;
+        let (i, path) = opt(|i| {
+            let (i, _) = multispace1(i)?;
+            let (i, _) = tag("--path")(i)?;
+            // The flag without a directory is an error rather than trailing text
+            cut(|i| {
+                let (i, _) = multispace1(i)?;
+                word(i)
+            })(i)
+        })(i)?;
 
         Ok((
             i,
             Command::Publish {
                 git: git.to_owned(),
                 refname: refname.map(ToString::to_string),
+                path: path.map(ToString::to_string),
+            },
+        ))
+    }
+
+    fn parse_yank(i: &str) -> IResult<&str, Command> {
+        let (i, yanked) = alt((value(true, tag("/yank")), value(false, tag("/unyank"))))(i)?;
+        let (i, _) = multispace1(i)?;
+
+        let (i, name) = map_res(word, str::parse::<PackageName>)(i)?;
+        let (i, _) = multispace1(i)?;
+        let (i, version) = map_res(word, Version::parse)(i)?;
+
+        Ok((
+            i,
+            Command::Yank {
+                name,
+                version,
+                yanked,
             },
         ))
     }
 
+    fn parse_owner(i: &str) -> IResult<&str, Command> {
+        let (i, add) = alt((
+            value(true, tag("/add-owner")),
+            value(false, tag("/remove-owner")),
+        ))(i)?;
+        let (i, _) = multispace1(i)?;
+
+        let (i, group) = namespace(i)?;
+        let (i, _) = multispace1(i)?;
+        let (i, _) = tag("@")(i)?;
+        let (i, user) = word(i)?;
+
+        Ok((
+            i,
+            Command::Owner {
+                group,
+                user: user.to_owned(),
+                add,
+            },
+        ))
+    }
+
+    fn parse_transfer(i: &str) -> IResult<&str, Command> {
+        let (i, _) = tag("/transfer-namespace")(i)?;
+        let (i, _) = multispace1(i)?;
+
+        let (i, group) = namespace(i)?;
+        let (i, _) = multispace1(i)?;
+        let (i, _) = tag("@")(i)?;
+        let (i, user) = word(i)?;
+
+        Ok((
+            i,
+            Command::TransferNamespace {
+                group,
+                user: user.to_owned(),
+            },
+        ))
+    }
+
+    fn parse_accept_transfer(i: &str) -> IResult<&str, Command> {
+        let (i, _) = tag("/accept-transfer")(i)?;
+        let (i, _) = multispace1(i)?;
+
+        let (i, group) = namespace(i)?;
+
+        Ok((i, Command::AcceptTransfer { group }))
+    }
+
+    fn parse_status(i: &str) -> IResult<&str, Command> {
+        value(Command::Status, tag("/status"))(i)
+    }
+
+    /// A namespace, normalized like the groups of package names
+    fn namespace(i: &str) -> IResult<&str, String> {
+        map_res(word, normalize_group)(i)
+    }
+
     fn word(i: &str) -> IResult<&str, &str> {
         take_while1(|c: char| !c.is_whitespace())(i)
     }
@@ -70,6 +195,7 @@ mod parse {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::controller::normalize_group;
 
     #[test]
     fn test_parse_command() {
@@ -81,6 +207,7 @@ mod test {
                 Some(Command::Publish {
                     git: "abc.xyz/zz.git".to_owned(),
                     refname: None,
+                    path: None,
                 }),
             ),
             (
@@ -88,6 +215,7 @@ mod test {
                 Some(Command::Publish {
                     git: "abc".to_owned(),
                     refname: None,
+                    path: None,
                 }),
             ),
             (
@@ -95,6 +223,7 @@ mod test {
                 Some(Command::Publish {
                     git: "abc.xyz/zz.git".to_owned(),
                     refname: Some("master".to_owned()),
+                    path: None,
                 }),
             ),
             (
@@ -102,8 +231,79 @@ mod test {
                 Some(Command::Publish {
                     git: "abc.xyz/zz.git".to_owned(),
                     refname: Some("master".to_owned()),
+                    path: None,
+                }),
+            ),
+            (
+                "@name /publish abc.xyz/zz.git --path sub/dir",
+                Some(Command::Publish {
+                    git: "abc.xyz/zz.git".to_owned(),
+                    refname: None,
+                    path: Some("sub/dir".to_owned()),
+                }),
+            ),
+            (
+                "@name /publish abc.xyz/zz.git v1.0 --path sub/dir",
+                Some(Command::Publish {
+                    git: "abc.xyz/zz.git".to_owned(),
+                    refname: Some("v1.0".to_owned()),
+                    path: Some("sub/dir".to_owned()),
+                }),
+            ),
+            (
+                "@name /yank group/pkg 1.2.3",
+                Some(Command::Yank {
+                    name: "group/pkg".parse().unwrap(),
+                    version: Version::parse("1.2.3").unwrap(),
+                    yanked: true,
+                }),
+            ),
+            (
+                "@name /unyank group/pkg 1.2.3",
+                Some(Command::Yank {
+                    name: "group/pkg".parse().unwrap(),
+                    version: Version::parse("1.2.3").unwrap(),
+                    yanked: false,
+                }),
+            ),
+            (
+                "@name /add-owner group @someone",
+                Some(Command::Owner {
+                    group: "group".to_owned(),
+                    user: "someone".to_owned(),
+                    add: true,
+                }),
+            ),
+            (
+                "@name /remove-owner group @someone",
+                Some(Command::Owner {
+                    group: "group".to_owned(),
+                    user: "someone".to_owned(),
+                    add: false,
+                }),
+            ),
+            (
+                "@name /transfer-namespace group @someone",
+                Some(Command::TransferNamespace {
+                    group: "group".to_owned(),
+                    user: "someone".to_owned(),
+                }),
+            ),
+            (
+                "@name /accept-transfer group",
+                Some(Command::AcceptTransfer {
+                    group: "group".to_owned(),
+                }),
+            ),
+            (
+                "@name /add-owner Some_Group @someone",
+                Some(Command::Owner {
+                    group: normalize_group("Some_Group").unwrap(),
+                    user: "someone".to_owned(),
+                    add: true,
                 }),
             ),
+            ("@name /status", Some(Command::Status)),
         ];
 
         for (text, expected) in cases {
@@ -118,6 +318,16 @@ mod test {
             "@name /publis abc",
             "@name / abc",
             "@name/publish abc.xyz/zz.git",
+            "@name /publish abc.xyz/zz.git --path",
+            "@name /publish abc.xyz/zz.git master --path ",
+            "@name /yank group/pkg",
+            "@name /yank pkg 1.2.3",
+            "@name /unyank group/pkg 1.2",
+            "@name /add-owner group someone",
+            "@name /remove-owner @someone",
+            "@name /transfer-namespace group",
+            "@name /accept-transfer",
+            "@name /accept-transfer b@d",
         ];
 
         for text in cases {
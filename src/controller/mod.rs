@@ -1,55 +1,93 @@
 mod command;
+mod owner;
 mod publish;
+mod status;
+#[cfg(test)]
+mod test;
+mod transfer;
+mod yank;
 
+use std::collections::HashSet;
 use std::fmt::Write;
+use std::net::TcpListener;
 use std::sync::Arc;
 
-use log::info;
-use rusqlite::Connection;
-use tokio::sync::Mutex;
+use chrono::{DateTime, FixedOffset, Utc};
+use elba::package::Name as PackageName;
+use failure::bail;
+use log::{info, warn};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::block_in_place;
 
 use self::command::Command;
-use crate::config::CONFIG;
+use self::publish::{PublishState, PublishStep, SideEffect};
+use crate::config::{IndexPublishMode, ListenMode, ReportMode, CONFIG};
 use crate::database::{self, Database};
-use crate::error::Result;
-use crate::github::{self, Comment, Github};
-use crate::workspace::Workspace;
+use crate::error::{Error, Result};
+use crate::github::{self, Comment, Github, GithubApi, Reaction};
+use crate::webhook;
+use crate::workspace::{Workspace, README_BRANCH};
 
 pub struct Controller {
-    github: Arc<Github>,
+    github: Arc<dyn GithubApi>,
     database: Mutex<Database>,
     workspace: Mutex<Workspace>,
+    /// Issues known to carry `INDEX_ISSUE_LABEL`
+    labelled_issues: Mutex<HashSet<i64>>,
+    /// `REPORT_MODE`, which tests switch
+    report_mode: ReportMode,
+    /// `INDEX_PUBLISH_MODE`, which tests switch
+    publish_mode: IndexPublishMode,
+    /// Make publishes fail once they have made this many side effects
+    #[cfg(test)]
+    fail_publish_after: std::sync::Mutex<Option<usize>>,
 }
 
 impl Controller {
     pub async fn new() -> Result<Self> {
-        let github = Arc::new(Github::new().await?);
+        Controller::with_github(Arc::new(Github::new().await?))
+    }
+
+    /// Create a controller talking to the given Github
+    pub fn with_github(github: Arc<dyn GithubApi>) -> Result<Self> {
         let workspace = Mutex::new(Workspace::new()?);
-        let database = Database::new(Connection::open(&CONFIG.db_path)?);
-        database.create_tables()?;
-        let database = Mutex::new(database);
+        let database = Mutex::new(Database::open(&CONFIG.db_path)?);
         Ok(Controller {
             github,
             database,
             workspace,
+            labelled_issues: Mutex::new(HashSet::new()),
+            report_mode: CONFIG.report_mode,
+            publish_mode: CONFIG.index_publish_mode,
+            #[cfg(test)]
+            fail_publish_after: std::sync::Mutex::new(None),
         })
     }
 
-    pub async fn run(self: Arc<Self>) -> Result<()> {
+    /// Execute the commands in new comments, which are received on the listener
+    /// in webhook mode
+    pub async fn run(self: Arc<Self>, listener: Option<TcpListener>) -> Result<()> {
+        match CONFIG.listen_mode {
+            ListenMode::Poll => self.run_poll().await,
+            ListenMode::Webhook => {
+                let listener = listener.ok_or(Error::MissingConfig("WEBHOOK_ADDR"))?;
+                let secret = CONFIG
+                    .webhook_secret
+                    .clone()
+                    .ok_or(Error::MissingConfig("WEBHOOK_SECRET"))?;
+                self.run_webhook(listener, secret).await
+            }
+        }
+    }
+
+    async fn run_poll(self: Arc<Self>) -> Result<()> {
         info!("Start polling issue comments");
-        let mut last_date = None;
+        // Comments posted while the bot was down are listed by the first poll,
+        // unless nothing has been recorded yet
+        let mut last_date = self.last_comment_date().await?;
         loop {
             // Poll comments from github issue
-            let resp = self
-                .github
-                .query_poll(
-                    github::url::issue_comments(
-                        &CONFIG.index_repo_name,
-                        &CONFIG.index_issue_number,
-                    ),
-                    &[("since", &last_date)],
-                )
-                .await?;
+            let resp = self.github.poll_issue_comments(last_date).await?;
 
             if last_date.is_none() {
                 last_date = Some(resp.date);
@@ -62,71 +100,420 @@ impl Controller {
                 if comment.created_at < last_date.unwrap() - chrono::Duration::minutes(1) {
                     continue;
                 }
-                // Don't reply myself
-                if comment.user.id == self.github.viewer_id() {
-                    continue;
+                self.handle_comment(comment).await?;
+            }
+
+            last_date = Some(resp.date);
+        }
+    }
+
+    async fn run_webhook(self: Arc<Self>, listener: TcpListener, secret: String) -> Result<()> {
+        info!("Start receiving issue comments from webhook");
+        let (sender, mut receiver) = mpsc::channel(64);
+        // The server stops once `_stop` is dropped on return, so that it doesn't
+        // answer deliveries of the listener for a controller which is gone
+        let (_stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(webhook::serve(listener, secret, sender, async {
+            stopped.await.ok();
+        }));
+
+        // Deliveries missed while the bot was down are not redelivered, so
+        // list the comments posted since then
+        if let Some(last_date) = self.last_comment_date().await? {
+            if let Some(resp) = self.github.issue_comments(Some(last_date)).await? {
+                info!(
+                    "Catching up {} comments since {}",
+                    resp.val.len(),
+                    last_date
+                );
+                for comment in resp.val {
+                    if comment.created_at >= last_date {
+                        self.handle_comment(comment).await?;
+                    }
                 }
-                if self
-                    .database
-                    .lock()
-                    .await
-                    .query_comment(comment.id)?
-                    .is_some()
-                {
-                    continue;
+            }
+        }
+
+        while let Some(comment) = receiver.recv().await {
+            self.handle_comment(comment).await?;
+        }
+
+        server.await??;
+        Ok(())
+    }
+
+    /// Creation date of the last comment recorded by previous runs
+    async fn last_comment_date(&self) -> Result<Option<DateTime<FixedOffset>>> {
+        let last_comment = self.database.lock().await.query_last_comment()?;
+        Ok(last_comment.map(|comment| comment.created_at))
+    }
+
+    /// Whether commands are accepted on the issue, which is either the index
+    /// issue or an issue carrying `INDEX_ISSUE_LABEL`
+    ///
+    /// Labels are looked up once per issue carrying it, while an issue without
+    /// it is checked again in case the label is added later.
+    async fn is_command_issue(&self, issue_number: i64) -> Result<bool> {
+        if CONFIG.index_issue_number == Some(issue_number) {
+            return Ok(true);
+        }
+        let label = match &CONFIG.index_issue_label {
+            Some(label) => label,
+            None => return Ok(false),
+        };
+        if self.labelled_issues.lock().await.contains(&issue_number) {
+            return Ok(true);
+        }
+
+        let labels = self.github.issue_labels(issue_number).await?;
+        if labels.contains(label) {
+            self.labelled_issues.lock().await.insert(issue_number);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Record a new comment and execute the command in it
+    async fn handle_comment(self: &Arc<Self>, comment: Comment) -> Result<()> {
+        // Don't reply myself
+        if comment.user.id == self.github.viewer_id() {
+            return Ok(());
+        }
+        if self
+            .database
+            .lock()
+            .await
+            .query_comment(comment.id)?
+            .is_some()
+        {
+            return Ok(());
+        }
+        if !self.is_command_issue(comment.issue_number).await? {
+            return Ok(());
+        }
+        // Save comment records
+        {
+            let database = self.database.lock().await;
+            database.insert_user(database::User {
+                id: comment.user.id,
+                name: comment.user.name.clone(),
+            })?;
+            database.insert_comment(database::Comment {
+                id: comment.id,
+                user_id: comment.user.id,
+                body: comment.body.clone(),
+                created_at: comment.created_at,
+                issue_number: Some(comment.issue_number),
+            })?;
+        }
+
+        // Parse command from comment
+        let command = match Command::from_str(&comment.body) {
+            Ok(Some(command)) => command,
+            Ok(None) => return Ok(()),
+            Err(_) => {
+                self.update_report(&comment, &CommandError).await?;
+                self.react(&comment, Reaction::Confused).await;
+                return Ok(());
+            }
+        };
+
+        // Don't execute commands left unattended for too long, which the
+        // commenter may not expect to happen any more
+        let age = Utc::now().signed_duration_since(comment.created_at);
+        if age > chrono::Duration::hours(CONFIG.command_max_age_hours) {
+            info!("Command expired: {:?}", command);
+            self.update_report(&comment, &CommandExpired).await?;
+            self.react(&comment, Reaction::ThumbsDown).await;
+            return Ok(());
+        }
+
+        // Record the job before executing it
+        self.database.lock().await.insert_job(database::Job {
+            comment_id: comment.id,
+            command: serde_json::to_string(&command)?,
+            step: None,
+            effects: None,
+            error: None,
+            finished: false,
+        })?;
+        self.react(&comment, Reaction::Eyes).await;
+
+        self.execute(command, comment);
+
+        Ok(())
+    }
+
+    fn execute(self: &Arc<Self>, command: Command, comment: Comment) {
+        info!("Executing command: {:?}", command);
+        let this = self.clone();
+        match command {
+            Command::Publish { git, refname, path } => {
+                tokio::task::spawn(async move { this.publish(git, refname, path, comment).await });
+            }
+            Command::Yank {
+                name,
+                version,
+                yanked,
+            } => {
+                tokio::task::spawn(async move { this.yank(name, version, yanked, comment).await });
+            }
+            Command::Owner { group, user, add } => {
+                tokio::task::spawn(
+                    async move { this.update_owner(group, user, add, comment).await },
+                );
+            }
+            Command::TransferNamespace { group, user } => {
+                tokio::task::spawn(
+                    async move { this.transfer_namespace(group, user, comment).await },
+                );
+            }
+            Command::AcceptTransfer { group } => {
+                tokio::task::spawn(async move { this.accept_transfer(group, comment).await });
+            }
+            Command::Status => {
+                tokio::task::spawn(async move { this.status(comment).await });
+            }
+        }
+    }
+
+    /// Resume the jobs left unfinished by the last run
+    ///
+    /// A publish that has not uploaded anything yet is started over, and one
+    /// waiting for its pull request keeps waiting, while others are reported as
+    /// failed after rolling back the side effects recorded in the job. Other
+    /// commands are idempotent so they are simply executed again.
+    pub async fn resume_jobs(self: &Arc<Self>) -> Result<()> {
+        let jobs = self.database.lock().await.query_unfinished_jobs()?;
+        for job in jobs {
+            let comment = {
+                let database = self.database.lock().await;
+                let comment = database.query_comment(job.comment_id)?.unwrap();
+                let user = database.query_user(comment.user_id)?.unwrap();
+                Comment {
+                    id: comment.id,
+                    user: github::User {
+                        id: user.id,
+                        name: user.name,
+                    },
+                    body: comment.body,
+                    created_at: comment.created_at,
+                    issue_number: comment
+                        .issue_number
+                        .or(CONFIG.index_issue_number)
+                        .unwrap_or_default(),
                 }
-                // Save comment records
+            };
+            let command: Command = serde_json::from_str(&job.command)?;
+            let step: Option<PublishStep> = job
+                .step
+                .map(|step| serde_json::from_str(&step))
+                .transpose()?;
+
+            match command {
+                Command::Publish { git, path, .. }
+                    if step.as_ref() >= Some(&PublishStep::Upload) =>
                 {
-                    let database = self.database.lock().await;
-                    database.insert_user(database::User {
-                        id: comment.user.id,
-                        name: comment.user.name.clone(),
-                    })?;
-                    database.insert_comment(database::Comment {
-                        id: comment.id,
-                        user_id: comment.user.id,
-                        body: comment.body.clone(),
-                        created_at: comment.created_at,
-                    })?;
+                    let effects: Vec<SideEffect> = job
+                        .effects
+                        .map(|effects| serde_json::from_str(&effects))
+                        .transpose()?
+                        .unwrap_or_default();
+                    let state = PublishState {
+                        step: step.unwrap(),
+                        remote_url: git,
+                        path,
+                        name: None,
+                        pull_request: None,
+                        error: None,
+                    };
+                    if state.step == PublishStep::Review {
+                        info!("Resuming review of interrupted command: {:?}", job.command);
+                        let this = self.clone();
+                        tokio::task::spawn(async move {
+                            this.resume_review(&comment, state, effects).await
+                        });
+                    } else {
+                        info!("Failing interrupted command: {:?}", job.command);
+                        self.rollback_interrupted(&comment, state, effects).await?;
+                    }
+                }
+                command => {
+                    info!("Resuming interrupted command: {:?}", job.command);
+                    self.execute(command, comment);
                 }
+            }
+        }
+        Ok(())
+    }
 
-                // Parse command from comment
-                let command = match Command::from_str(&comment.body) {
-                    Ok(Some(command)) => command,
-                    Ok(None) => continue,
-                    Err(_) => {
-                        self.update_report(&comment, &CommandError).await?;
-                        continue;
-                    }
-                };
+    /// Record the job as finished, then show the final report
+    ///
+    /// The job is finished first so that it's not resumed or failed again on
+    /// the next start if reporting fails, which is only logged as the command
+    /// itself is done.
+    async fn finish_job<R: CommentReport>(
+        &self,
+        comment: &Comment,
+        report: &R,
+        error: Option<&str>,
+    ) -> Result<()> {
+        self.database.lock().await.finish_job(comment.id, error)?;
+        if let Err(report_error) = self.update_report(comment, report).await {
+            warn!(
+                "Failed to report the end of command {}: {}",
+                comment.id, report_error
+            );
+        }
+        let reaction = match error {
+            Some(_) => Reaction::ThumbsDown,
+            None => Reaction::Rocket,
+        };
+        self.react(comment, reaction).await;
+        Ok(())
+    }
 
-                // Execute command
-                info!("Executing command: {:?}", command);
-                match command {
-                    Command::Publish { git, refname } => {
-                        let this = self.clone();
-                        tokio::task::spawn(
-                            async move { this.publish(git, refname, comment).await },
-                        );
-                    }
+    /// Replace the reaction of the bot on the comment
+    ///
+    /// Reactions are only a hint for the commenter, so failures are logged
+    /// rather than failing the command.
+    async fn react(&self, comment: &Comment, reaction: Reaction) {
+        let res: Result<()> = try {
+            let current = self.database.lock().await.query_reaction(comment.id)?;
+            if let Some(current) = &current {
+                if current.content == reaction.content() {
+                    return;
                 }
             }
 
-            last_date = Some(resp.date);
+            let reaction_id = self.github.create_reaction(comment.id, reaction).await?;
+            if let Some(current) = current {
+                self.github
+                    .delete_reaction(comment.id, current.reaction_id)
+                    .await?;
+            }
+            self.database
+                .lock()
+                .await
+                .insert_reaction(database::Reaction {
+                    comment_id: comment.id,
+                    reaction_id,
+                    content: reaction.content().to_owned(),
+                })?;
+        };
+        if let Err(error) = res {
+            warn!(
+                "Failed to react {:?} to comment {}: {}",
+                reaction, comment.id, error
+            );
+        }
+    }
+
+    /// Query database and check whether the user is an owner of the namespace
+    async fn check_namespace_owner(&self, group: &str, user: &github::User) -> Result<()> {
+        let owners = self.database.lock().await.query_namespace_owners(group)?;
+        if !owners.iter().any(|owner| owner.id == user.id) {
+            bail!(Error::NotNamespaceOwner {
+                group: group.to_string(),
+            });
         }
+        Ok(())
     }
 
+    /// Render the package list from database into the index README
+    ///
+    /// In pull request mode the README is pushed to `README_BRANCH` instead,
+    /// whose pull request is opened once and kept up to date by later renders
+    /// until a maintainer merges it.
+    pub async fn regen_readme(&self) -> Result<()> {
+        let workspace = self.workspace.lock().await;
+        let package_list = render_readme_package_list(&*self.database.lock().await)?;
+        match self.publish_mode {
+            IndexPublishMode::Push => {
+                block_in_place(|| workspace.index.update_readme(package_list))?;
+            }
+            IndexPublishMode::PullRequest => {
+                let diff = block_in_place(|| workspace.index.propose_readme(package_list))?;
+                if let Some(diff) = diff {
+                    let pull_request = self
+                        .propose_branch(
+                            README_BRANCH,
+                            workspace.index.branch(),
+                            "Update README",
+                            &format!("```diff\n{}```\n", diff),
+                        )
+                        .await?;
+                    info!("README proposed by {}", pull_request.html_url);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Open a pull request to merge the branch into `base`, unless one is open
+    /// for it already, which gets the latest push of the branch anyway
+    async fn propose_branch(
+        &self,
+        branch: &str,
+        base: &str,
+        title: &str,
+        body: &str,
+    ) -> Result<github::PullRequest> {
+        match self.github.branch_pull_request(branch).await? {
+            Some(pull_request) => Ok(pull_request),
+            None => {
+                self.github
+                    .create_pull_request(branch, base, title, body)
+                    .await
+            }
+        }
+    }
+
+    pub async fn query_user(&self, user_name: &str) -> Result<github::User> {
+        self.github.query_user(user_name).await
+    }
+
+    /// Show the report of the command to the commenter
+    ///
+    /// In reply mode, the first report is posted as a new comment of the bot,
+    /// which later reports replace.
     async fn update_report<R: CommentReport>(&self, comment: &Comment, report: &R) -> Result<()> {
-        let report = report.render(&comment);
-        self.github.update_comment(comment.id, report).await?;
+        let report = report.render(&comment, self.report_mode);
+        match self.report_mode {
+            ReportMode::Edit => self.github.update_comment(comment.id, report).await?,
+            ReportMode::Reply => {
+                let reply_id = self.database.lock().await.query_reply(comment.id)?;
+                match reply_id {
+                    Some(reply_id) => self.github.update_comment(reply_id, report).await?,
+                    None => {
+                        let reply = self
+                            .github
+                            .create_comment(comment.issue_number, report)
+                            .await?;
+                        self.database
+                            .lock()
+                            .await
+                            .insert_reply(comment.id, reply.id)?;
+                    }
+                }
+            }
+        }
         Ok(())
     }
 }
 
 trait CommentReport {
-    fn render(&self, comment: &Comment) -> String {
+    fn render(&self, comment: &Comment, mode: ReportMode) -> String {
         let mut report = String::new();
-        write!(report, "{}", &comment.body).unwrap();
+        match mode {
+            ReportMode::Edit => write!(report, "{}", &comment.body).unwrap(),
+            ReportMode::Reply => {
+                for line in comment.body.lines() {
+                    writeln!(report, "> {}", line).unwrap();
+                }
+            }
+        }
         write!(report, "\n\n- - - - - - - - - - -\n\n").unwrap();
         if let Some(title) = self.render_title(&comment) {
             write!(report, "#### *{}*\n\n", title).unwrap();
@@ -165,18 +552,49 @@ impl CommentReport for CommandError {
     }
 }
 
-fn render_readme_package_list(database: &Database) -> Result<String> {
+struct CommandExpired;
+
+impl CommentReport for CommandExpired {
+    fn render_title(&self, _: &Comment) -> Option<&str> {
+        Some("Command Expired")
+    }
+
+    fn render_body(&self, _: &Comment) -> Option<String> {
+        None
+    }
+
+    fn render_msg(&self, _: &Comment) -> String {
+        format!(
+            "elba-bot was not available in {} hours after your command, please comment again.",
+            CONFIG.command_max_age_hours
+        )
+    }
+}
+
+/// Validate and normalize a namespace the same way as the group of package
+/// names, so that commands naming it match the packages published into it
+pub fn normalize_group(group: &str) -> Result<String> {
+    // Groups follow the same rules as names, so the group is its own name here
+    let name: PackageName = format!("{}/{}", group, group)
+        .parse()
+        .map_err(|_| Error::InvalidNamespace(group.to_owned()))?;
+    Ok(name.normalized_group().to_owned())
+}
+
+pub fn render_readme_package_list(database: &Database) -> Result<String> {
     let mut body = String::new();
 
     let mut packages: Vec<database::Package> = database.query_package(None)?;
-    packages
-        .sort_by(|a, b| (&a.group, &a.name, &b.version).cmp(&((&b.group, &b.name, &a.version))));
+    // List the latest unyanked version of each package, or the latest yanked one if all are yanked
+    packages.sort_by(|a, b| {
+        (&a.group, &a.name, a.yanked, &b.version).cmp(&(&b.group, &b.name, b.yanked, &a.version))
+    });
     packages.dedup_by(|a, b| (&a.group, &a.name).eq(&(&b.group, &b.name)));
     // packages.sort_by(|a, b| b.version.cmp(&a.version));
 
     for package in packages {
         let user_name = database.query_user(package.user_id)?.unwrap().name;
-        let package_link = if let Some(url) = package.homepage.or(package.repository) {
+        let mut package_link = if let Some(url) = package.homepage.or(package.repository) {
             format!(
                 "[`{}/{} {}`]({})",
                 package.group, package.name, package.version, url
@@ -184,6 +602,9 @@ fn render_readme_package_list(database: &Database) -> Result<String> {
         } else {
             format!("`{}/{} {}`", package.group, package.name, package.version)
         };
+        if package.yanked {
+            package_link = format!("~~{}~~ *(yanked)*", package_link);
+        }
         let package_description = if let Some(description) = package.description {
             format!(" *{}* ", description)
         } else {
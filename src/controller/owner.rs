@@ -0,0 +1,204 @@
+use std::fmt::Write;
+
+use failure::bail;
+
+use super::*;
+use crate::error::{Error, Result};
+use crate::github::Comment;
+
+impl Controller {
+    pub async fn update_owner(
+        &self,
+        group: String,
+        user_name: String,
+        add: bool,
+        comment: Comment,
+    ) -> Result<()> {
+        let mut state = OwnerState {
+            group: group.clone(),
+            user_name: user_name.clone(),
+            add,
+            done: false,
+            error: None,
+        };
+
+        let res: Result<()> = try {
+            self.check_namespace_owner(&group, &comment.user).await?;
+
+            let user = self.github.query_user(&user_name).await?;
+            update_owners(&*self.database.lock().await, &group, &user, add)?;
+        };
+
+        match res {
+            Ok(()) => {
+                state.done = true;
+                info!("Update owner done: {:?}", state);
+            }
+            Err(error) => {
+                state.error = Some(error.to_string());
+                info!("Update owner error: {:?}", state);
+            }
+        }
+        self.finish_job(&comment, &state, state.error.as_deref())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Make the user the only owner of the namespace
+    pub async fn set_owner(&self, group: &str, user_name: &str) -> Result<()> {
+        let user = self.github.query_user(user_name).await?;
+        let database = self.database.lock().await;
+        database.insert_user(database::User {
+            id: user.id,
+            name: user.name.clone(),
+        })?;
+        database.set_namespace_owner(group, user.id)?;
+        info!("@{} is now the owner of namespace `{}`", user.name, group);
+        Ok(())
+    }
+}
+
+/// Add the user to the owners of the namespace, or remove them from the owners
+/// while keeping at least one
+fn update_owners(database: &Database, group: &str, user: &github::User, add: bool) -> Result<()> {
+    if add {
+        database.insert_user(database::User {
+            id: user.id,
+            name: user.name.clone(),
+        })?;
+        database.insert_namespace_owner(group, user.id)?;
+    } else {
+        let owners = database.query_namespace_owners(group)?;
+        if !owners.iter().any(|owner| owner.id == user.id) {
+            bail!(Error::NotAnOwner {
+                group: group.to_owned(),
+                user: user.name.clone(),
+            });
+        }
+        if owners.len() == 1 {
+            bail!(Error::LastNamespaceOwner {
+                group: group.to_owned(),
+                user: user.name.clone(),
+            });
+        }
+        database.delete_namespace_owner(group, user.id)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct OwnerState {
+    pub group: String,
+    pub user_name: String,
+    pub add: bool,
+    pub done: bool,
+    pub error: Option<String>,
+}
+
+impl CommentReport for OwnerState {
+    fn render_title(&self, _: &Comment) -> Option<&str> {
+        if self.add {
+            Some("Add Namespace Owner")
+        } else {
+            Some("Remove Namespace Owner")
+        }
+    }
+
+    fn render_body(&self, _: &Comment) -> Option<String> {
+        let mut body = String::new();
+
+        if let Some(error) = &self.error {
+            write!(body, "- ❌ *{}*\n\n", error).unwrap();
+        } else if self.done {
+            body += "- ✔️ Done\n";
+        }
+
+        Some(body)
+    }
+
+    fn render_msg(&self, _: &Comment) -> String {
+        if let Some(_) = &self.error {
+            "Updating owner failed due to the reason above.".to_owned()
+        } else if self.add {
+            format!(
+                "@{} is now an owner of namespace `{}`.",
+                self.user_name, self.group
+            )
+        } else {
+            format!(
+                "@{} is no longer an owner of namespace `{}`.",
+                self.user_name, self.group
+            )
+        }
+    }
+}
+
+/// Render owners in the form of `@foo, @bar`
+pub fn render_owners(owners: &[database::User]) -> String {
+    owners
+        .iter()
+        .map(|owner| format!("@{}", owner.name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    fn user(id: i64, name: &str) -> github::User {
+        github::User {
+            id,
+            name: name.to_owned(),
+        }
+    }
+
+    fn owner_ids(database: &Database, group: &str) -> Vec<i64> {
+        database
+            .query_namespace_owners(group)
+            .unwrap()
+            .iter()
+            .map(|owner| owner.id)
+            .collect()
+    }
+
+    fn error_of(res: Result<()>) -> Error {
+        match res.unwrap_err().downcast::<Error>() {
+            Ok(error) => error,
+            Err(error) => panic!("unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn test_update_owners() {
+        let database = Database::new(Connection::open_in_memory().unwrap());
+        database.create_tables().unwrap();
+        let (alice, bob, carol) = (user(1, "alice"), user(2, "bob"), user(3, "carol"));
+
+        update_owners(&database, "group", &alice, true).unwrap();
+        update_owners(&database, "group", &bob, true).unwrap();
+        assert_eq!(owner_ids(&database, "group"), [1, 2]);
+
+        // Adding an owner again changes nothing
+        update_owners(&database, "group", &bob, true).unwrap();
+        assert_eq!(owner_ids(&database, "group"), [1, 2]);
+
+        update_owners(&database, "group", &alice, false).unwrap();
+        assert_eq!(owner_ids(&database, "group"), [2]);
+
+        match error_of(update_owners(&database, "group", &bob, false)) {
+            Error::LastNamespaceOwner { group, user } => {
+                assert_eq!((&*group, &*user), ("group", "bob"))
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+        match error_of(update_owners(&database, "group", &carol, false)) {
+            Error::NotAnOwner { group, user } => assert_eq!((&*group, &*user), ("group", "carol")),
+            error => panic!("unexpected error: {}", error),
+        }
+        assert_eq!(owner_ids(&database, "group"), [2]);
+    }
+}
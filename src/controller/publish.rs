@@ -1,91 +1,544 @@
 use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 
 use elba::package::{
     manifest::{DepReq, Manifest},
     Name as PackageName,
 };
 use failure::bail;
+use log::error;
 use semver::Version;
+use serde::{Deserialize, Serialize};
 use tokio::task::block_in_place;
+use tokio::time::delay_for;
 
 use super::*;
-use crate::config::CONFIG;
+use crate::config::{GitAuth, IndexPublishMode, CONFIG};
 use crate::database::{self};
 use crate::error::{Error, Result};
-use crate::github::{self, Comment};
-use crate::workspace::Repo;
+use crate::github::{self, CheckState, Comment};
+use crate::workspace::{Repo, Workspace};
+
+/// How often to check the pull request of a publish
+#[cfg(not(test))]
+const PULL_REQUEST_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Tests approve pull requests while they are waited for
+#[cfg(test)]
+const PULL_REQUEST_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 impl Controller {
     pub async fn publish(
         &self,
         remote_url: String,
         refname: Option<String>,
+        path: Option<String>,
         comment: Comment,
     ) -> Result<()> {
         let mut state = PublishState {
             step: PublishStep::Block,
             remote_url: remote_url.clone(),
+            path: path.clone(),
             name: None,
+            pull_request: None,
             error: None,
         };
 
         let res: Result<()> = try {
-            self.update_report(&comment, &state).await?;
+            self.report_publish(Some(&comment), &state).await?;
 
-            let workspace = self.workspace.lock().await;
+            let source = PublishSource::Remote {
+                url: remote_url,
+                refname,
+                path,
+            };
+            self.publish_transaction(&source, &comment.user, Some(&comment), &mut state)
+                .await?
+        };
+
+        match res {
+            Ok(()) => {
+                state.step = PublishStep::Done;
+                info!("Publish done: {:?}", state);
+            }
+            Err(error) => {
+                state.error = Some(error.to_string());
+                info!("Publish error: {:?}", state);
+            }
+        }
+        self.finish_job(&comment, &state, state.error.as_deref())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Publish the package in a local directory on behalf of the publisher
+    pub async fn publish_local(&self, path: PathBuf, publisher: github::User) -> Result<()> {
+        let mut state = PublishState {
+            step: PublishStep::Block,
+            remote_url: path.display().to_string(),
+            path: None,
+            name: None,
+            pull_request: None,
+            error: None,
+        };
+
+        self.publish_transaction(&PublishSource::Local(path), &publisher, None, &mut state)
+            .await?;
 
-            // Pull remote repository
-            state.step = PublishStep::Pull;
-            self.update_report(&comment, &state).await?;
-            let pull_dir = tempdir::TempDir::new(&CONFIG.bot_name)?;
-            let pull_repo = block_in_place(|| Repo::clone(&remote_url, pull_dir.as_ref()))?;
-            if let Some(refname) = refname {
-                pull_repo.checkout(&refname)?;
+        state.step = PublishStep::Done;
+        info!("Publish done: {:?}", state);
+
+        Ok(())
+    }
+
+    /// Run the publish steps, and roll back the side effects made if any step
+    /// fails before the pull request of the publish is merged
+    ///
+    /// The workspace is held while running the steps, but not while waiting for
+    /// the pull request of the publish, which may take days.
+    async fn publish_transaction(
+        &self,
+        source: &PublishSource,
+        publisher: &github::User,
+        comment: Option<&Comment>,
+        state: &mut PublishState,
+    ) -> Result<()> {
+        let mut effects = Vec::new();
+        let res: Result<Option<i64>> = try {
+            let pull_request = {
+                let workspace = self.workspace.lock().await;
+                self.publish_steps(&workspace, source, publisher, comment, state, &mut effects)
+                    .await?
+            };
+            if let Some(number) = pull_request {
+                self.wait_pull_request(number).await?;
             }
+            pull_request
+        };
 
-            // Build package tarball and check manifest
-            state.step = PublishStep::Verify;
-            self.update_report(&comment, &state).await?;
-            let (tarball, manifest) =
-                block_in_place(|| elba::cli::index::package(pull_repo.workdir()?))?;
+        match res {
+            Ok(Some(_)) => self.finish_review(effects).await,
+            Ok(None) => Ok(()),
+            Err(error) => {
+                let workspace = self.workspace.lock().await;
+                if let Err(rollback_error) = self.rollback(&workspace, effects).await {
+                    error!("Publish rollback failure: {}", rollback_error);
+                }
+                Err(error)
+            }
+        }
+    }
 
-            self.check_publish_permission(&manifest, &comment.user)
+    /// Run the publish steps, recording every side effect made so that they can
+    /// be undone if a later step fails
+    ///
+    /// Returns the pull request to wait for if the index is updated by one.
+    async fn publish_steps(
+        &self,
+        workspace: &Workspace,
+        source: &PublishSource,
+        publisher: &github::User,
+        comment: Option<&Comment>,
+        state: &mut PublishState,
+        effects: &mut Vec<SideEffect>,
+    ) -> Result<Option<i64>> {
+        // Pull remote repository
+        let pull_dir = tempdir::TempDir::new(&CONFIG.bot_name)?;
+        let package_dir = match source {
+            PublishSource::Remote { url, refname, path } => {
+                state.step = PublishStep::Pull;
+                self.report_publish(comment, state).await?;
+                let pull_repo = block_in_place(|| {
+                    Repo::clone(url, pull_dir.as_ref(), None, GitAuth::Anonymous, None)
+                })?;
+                if let Some(refname) = refname {
+                    pull_repo.checkout(refname)?;
+                }
+                match path {
+                    Some(path) => package_subdir(pull_repo.workdir()?, path)?,
+                    None => pull_repo.workdir()?.to_path_buf(),
+                }
+            }
+            PublishSource::Local(path) => path.clone(),
+        };
+
+        // Build package tarball and check manifest
+        state.step = PublishStep::Verify;
+        self.report_publish(comment, state).await?;
+        let (tarball, manifest) = block_in_place(|| elba::cli::index::package(&package_dir))?;
+
+        self.check_publish_permission(&manifest, publisher).await?;
+        let name = manifest.package.name.clone();
+        let version = manifest.package.version.clone();
+        state.name = Some((name.clone(), version.clone()));
+
+        // Upload talball to store repository
+        state.step = PublishStep::Upload;
+        self.report_publish(comment, state).await?;
+        let publisher_user = database::User {
+            id: publisher.id,
+            name: publisher.name.clone(),
+        };
+        let location = block_in_place(|| {
+            workspace
+                .store
+                .upload_package(&manifest, &tarball, &publisher_user)
+        })?;
+        self.record_effect(
+            comment,
+            effects,
+            SideEffect::Upload(name.clone(), version.clone()),
+        )
+        .await?;
+        let store_commit = workspace.store.commit_hash();
+
+        state.step = PublishStep::UpdateIndex;
+        self.report_publish(comment, state).await?;
+        let package = database::Package {
+            group: name.normalized_group().to_string(),
+            name: name.normalized_name().to_string(),
+            version: version.clone(),
+            description: manifest.package.description.clone(),
+            homepage: manifest.package.homepage.clone(),
+            repository: manifest.package.repository.clone(),
+            user_id: publisher.id,
+            yanked: false,
+            path: state.path.clone(),
+        };
+        match self.publish_mode {
+            // Commit the metadata into database, then the index entry and readme
+            IndexPublishMode::Push => {
+                let new_namespace = self.commit_publish(package, &publisher_user).await?;
+                self.record_effect(
+                    comment,
+                    effects,
+                    SideEffect::DatabaseRow(name.clone(), version.clone()),
+                )
+                .await?;
+                if new_namespace {
+                    self.record_effect(
+                        comment,
+                        effects,
+                        SideEffect::NamespaceOwner(
+                            name.normalized_group().to_string(),
+                            publisher.id,
+                        ),
+                    )
+                    .await?;
+                }
+                let package_list = render_readme_package_list(&*self.database.lock().await)?;
+                block_in_place(|| {
+                    workspace.index.update_package(
+                        &manifest,
+                        &location,
+                        &publisher_user,
+                        state.path.as_deref(),
+                        store_commit.as_deref(),
+                        package_list,
+                    )
+                })?;
+                self.record_effect(comment, effects, SideEffect::IndexEntry(name, version))
+                    .await?;
+                Ok(None)
+            }
+            // Propose the index entry, leaving the database and readme until the
+            // pull request is merged so that they don't list unmerged packages
+            IndexPublishMode::PullRequest => {
+                self.record_effect(
+                    comment,
+                    effects,
+                    SideEffect::Proposal(package, publisher_user.clone()),
+                )
                 .await?;
-            state.name = Some((
-                manifest.package.name.clone(),
-                manifest.package.version.clone(),
-            ));
-
-            // Upload talball to store repository
-            state.step = PublishStep::Upload;
-            self.update_report(&comment, &state).await?;
-            let location = block_in_place(|| workspace.store.upload_package(&manifest, &tarball))?;
-
-            // Update index entry and commit the metadata into database, then update readme
-            state.step = PublishStep::UpdateIndex;
-            self.update_report(&comment, &state).await?;
-            block_in_place(|| workspace.index.update_package(&manifest, &location))?;
-            self.commit_publish(&manifest, &comment.user).await?;
-            let package_list = render_readme_package_list(&*self.database.lock().await)?;
-            block_in_place(|| workspace.index.update_readme(package_list))?;
-
-            ()
+                let (branch, diff) = block_in_place(|| {
+                    workspace.index.propose_package(
+                        &manifest,
+                        &location,
+                        &publisher_user,
+                        state.path.as_deref(),
+                        store_commit.as_deref(),
+                    )
+                })?;
+                self.record_effect(comment, effects, SideEffect::Branch(branch.clone()))
+                    .await?;
+                let pull_request = self
+                    .github
+                    .create_pull_request(
+                        &branch,
+                        workspace.index.branch(),
+                        &format!(
+                            "Publish `{} {}`",
+                            manifest.package.name, manifest.package.version
+                        ),
+                        &format!(
+                            "Published by @{} from {}.\n\n```diff\n{}```\n",
+                            publisher.name, state.remote_url, diff
+                        ),
+                    )
+                    .await?;
+                self.record_effect(
+                    comment,
+                    effects,
+                    SideEffect::PullRequest(pull_request.number),
+                )
+                .await?;
+
+                state.step = PublishStep::Review;
+                state.pull_request = Some(pull_request.html_url.clone());
+                self.report_publish(comment, state).await?;
+                Ok(Some(pull_request.number))
+            }
+        }
+    }
+
+    /// Finish the publish whose pull request is merged, by deleting its branch,
+    /// inserting the package into database and proposing the readme listing it
+    ///
+    /// The index entry is merged already, so nothing is rolled back from here
+    /// on, and a database left behind is repaired by `fsck --repair`. A resumed
+    /// publish may have inserted the package before the restart.
+    async fn finish_review(&self, effects: Vec<SideEffect>) -> Result<()> {
+        let mut proposal = None;
+        for effect in effects {
+            match effect {
+                SideEffect::Branch(branch) => {
+                    // The branch is merged, so failing to delete it is harmless
+                    let workspace = self.workspace.lock().await;
+                    if let Err(error) = block_in_place(|| workspace.index.delete_branch(&branch)) {
+                        warn!("Failed to delete branch `{}`: {}", branch, error);
+                    }
+                }
+                SideEffect::Proposal(package, publisher) => proposal = Some((package, publisher)),
+                _ => (),
+            }
+        }
+
+        if let Some((package, publisher)) = proposal {
+            let published = self
+                .database
+                .lock()
+                .await
+                .query_package(Some(&package.group))?
+                .iter()
+                .any(|other| other.name == package.name && other.version == package.version);
+            if !published {
+                self.commit_publish(package, &publisher).await?;
+            }
+        }
+
+        // The package is published anyway, and `fsck` reports the readme left
+        // outdated
+        if let Err(error) = self.regen_readme().await {
+            warn!("Failed to update README: {}", error);
+        }
+
+        Ok(())
+    }
+
+    /// Wait until the pull request is merged
+    ///
+    /// The bot merges it once checks pass, and once a maintainer approves it
+    /// too unless auto merge is enabled. Maintainers may also merge it themselves.
+    pub async fn wait_pull_request(&self, number: i64) -> Result<()> {
+        loop {
+            let pull_request = self.github.pull_request(number).await?;
+            if pull_request.merged {
+                return Ok(());
+            }
+            if pull_request.state == "closed" {
+                bail!(Error::PullRequestClosed(number));
+            }
+
+            match self.github.commit_checks(&pull_request.head.sha).await? {
+                CheckState::Failure => bail!(Error::PullRequestChecksFailed(number)),
+                CheckState::Pending => (),
+                CheckState::Success => {
+                    if CONFIG.index_auto_merge || self.github.pull_request_approved(number).await? {
+                        self.github
+                            .merge_pull_request(number, &pull_request.head.sha)
+                            .await?;
+                        return Ok(());
+                    }
+                }
+            }
+
+            delay_for(PULL_REQUEST_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Record the side effect, and in the job too if the publish is requested
+    /// by a comment, so that it can be rolled back after a restart as well
+    async fn record_effect(
+        &self,
+        comment: Option<&Comment>,
+        effects: &mut Vec<SideEffect>,
+        effect: SideEffect,
+    ) -> Result<()> {
+        effects.push(effect);
+        if let Some(comment) = comment {
+            self.database
+                .lock()
+                .await
+                .update_job_effects(comment.id, &serde_json::to_string(effects)?)?;
+        }
+
+        #[cfg(test)]
+        {
+            if *self.fail_publish_after.lock().unwrap() == Some(effects.len()) {
+                bail!("Publish failed after {} side effects", effects.len());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resume waiting for the pull request of a publish interrupted by a
+    /// restart, from the side effects recorded in its job
+    ///
+    /// The side effects are rolled back if the pull request is not merged.
+    pub async fn resume_review(
+        &self,
+        comment: &Comment,
+        mut state: PublishState,
+        effects: Vec<SideEffect>,
+    ) -> Result<()> {
+        state.name = effects.iter().find_map(|effect| match effect {
+            SideEffect::Upload(name, version) => Some((name.clone(), version.clone())),
+            _ => None,
+        });
+        let pull_request = effects.iter().find_map(|effect| match effect {
+            SideEffect::PullRequest(number) => Some(*number),
+            _ => None,
+        });
+
+        let res: Result<()> = try {
+            let number = pull_request.ok_or(Error::JobInterrupted)?;
+            state.pull_request = Some(self.github.pull_request(number).await?.html_url);
+            self.report_publish(Some(comment), &state).await?;
+            self.wait_pull_request(number).await?;
+        };
+        let res = match res {
+            Ok(()) => self.finish_review(effects).await,
+            Err(error) => {
+                let workspace = self.workspace.lock().await;
+                if let Err(rollback_error) = self.rollback(&workspace, effects).await {
+                    error!("Publish rollback failure: {}", rollback_error);
+                }
+                Err(error)
+            }
         };
 
         match res {
             Ok(()) => {
                 state.step = PublishStep::Done;
-                self.update_report(&comment, &state).await?;
                 info!("Publish done: {:?}", state);
             }
             Err(error) => {
                 state.error = Some(error.to_string());
-                self.update_report(&comment, &state).await?;
                 info!("Publish error: {:?}", state);
             }
         }
+        self.finish_job(comment, &state, state.error.as_deref())
+            .await
+    }
 
-        Ok(())
+    /// Roll back a publish interrupted by a restart by the side effects
+    /// recorded in its job, and report it as failed
+    pub async fn rollback_interrupted(
+        &self,
+        comment: &Comment,
+        mut state: PublishState,
+        effects: Vec<SideEffect>,
+    ) -> Result<()> {
+        info!("Rolling back interrupted publish: {:?}", effects);
+        {
+            let workspace = self.workspace.lock().await;
+            if let Err(error) = self.rollback(&workspace, effects).await {
+                error!("Publish rollback failure: {}", error);
+            }
+        }
+
+        state.error = Some(Error::JobInterrupted.to_string());
+        self.finish_job(comment, &state, state.error.as_deref())
+            .await
+    }
+
+    /// Undo the side effects of a failed publish in reverse order
+    ///
+    /// Keep going when one fails so that as much as possible is undone, and
+    /// return the first error. The README listing the package is rendered again
+    /// once its row is gone as well.
+    async fn rollback(&self, workspace: &Workspace, effects: Vec<SideEffect>) -> Result<()> {
+        let mut res = Ok(());
+        let mut readme_outdated = false;
+        for effect in effects.into_iter().rev() {
+            info!("Rolling back publish: {:?}", effect);
+            let effect_res = match &effect {
+                SideEffect::Upload(name, version) => {
+                    block_in_place(|| workspace.store.delete_package(name, version))
+                }
+                SideEffect::IndexEntry(name, version) => {
+                    readme_outdated = true;
+                    block_in_place(|| workspace.index.remove_package(name, version))
+                }
+                SideEffect::Branch(branch) => {
+                    block_in_place(|| workspace.index.delete_branch(branch))
+                }
+                SideEffect::PullRequest(number) => self.github.close_pull_request(*number).await,
+                SideEffect::DatabaseRow(name, version) => self
+                    .database
+                    .lock()
+                    .await
+                    .delete_package(name.normalized_group(), name.normalized_name(), version),
+                SideEffect::NamespaceOwner(group, user_id) => self
+                    .database
+                    .lock()
+                    .await
+                    .delete_namespace_owner(group, *user_id),
+                // Nothing is inserted until the pull request is merged
+                SideEffect::Proposal(..) => Ok(()),
+            };
+            if let Err(error) = effect_res {
+                error!("Failed to roll back {:?}: {}", effect, error);
+                if res.is_ok() {
+                    res = Err(error);
+                }
+            }
+        }
+
+        if readme_outdated {
+            let readme_res: Result<()> = try {
+                let package_list = render_readme_package_list(&*self.database.lock().await)?;
+                block_in_place(|| workspace.index.update_readme(package_list))?;
+            };
+            if let Err(error) = readme_res {
+                error!("Failed to roll back README: {}", error);
+                if res.is_ok() {
+                    res = Err(error);
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Record the publish step of the job and update the comment report, if the
+    /// publish is requested by a comment
+    async fn report_publish(&self, comment: Option<&Comment>, state: &PublishState) -> Result<()> {
+        match comment {
+            Some(comment) => {
+                self.database
+                    .lock()
+                    .await
+                    .update_job_step(comment.id, &serde_json::to_string(&state.step)?)?;
+                self.update_report(comment, state).await
+            }
+            None => {
+                info!("Publish step: {:?}", state.step);
+                Ok(())
+            }
+        }
     }
 
     /// Query database and check whether the user has permission to publish
@@ -97,17 +550,13 @@ impl Controller {
         let database = self.database.lock().await;
         let all_packages = database.query_package(None)?;
 
-        // Check that the user should own the namespace
-        let conflict_package = all_packages
-            .iter()
-            .filter(|package| package.group == manifest.package.name.normalized_group())
-            .filter(|package| package.user_id != user.id)
-            .next();
-        if let Some(conflict_package) = conflict_package {
-            let namespace_owner = database.query_user(conflict_package.user_id)?.unwrap();
+        // Check that the user should own the namespace, unless it's not taken yet
+        let group = manifest.package.name.normalized_group();
+        let owners = database.query_namespace_owners(group)?;
+        if !owners.is_empty() && !owners.iter().any(|owner| owner.id == user.id) {
             bail!(Error::NamespaceIsTaken {
-                group: conflict_package.group.to_string(),
-                owner: namespace_owner.name
+                group: group.to_string(),
+                owners: owner::render_owners(&owners),
             });
         };
 
@@ -146,40 +595,100 @@ impl Controller {
     }
 
     /// Commit package metadata to database
-    async fn commit_publish(&self, manifest: &Manifest, user: &github::User) -> Result<()> {
+    ///
+    /// Returns whether the namespace was not taken before, in which case the
+    /// user becomes its owner.
+    async fn commit_publish(
+        &self,
+        package: database::Package,
+        user: &database::User,
+    ) -> Result<bool> {
         let database = self.database.lock().await;
+        let new_namespace = database.query_namespace_owners(&package.group)?.is_empty();
         database.insert_user(database::User {
             id: user.id,
             name: user.name.clone(),
         })?;
-        database.insert_package(database::Package {
-            group: manifest.package.name.normalized_group().to_string(),
-            name: manifest.package.name.normalized_name().to_string(),
-            version: manifest.package.version.clone(),
-            description: manifest.package.description.clone(),
-            homepage: manifest.package.homepage.clone(),
-            repository: manifest.package.repository.clone(),
-            user_id: user.id,
-        })?;
-        Ok(())
+        if new_namespace {
+            database.insert_namespace_owner(&package.group, user.id)?;
+        }
+        database.insert_package(package)?;
+        Ok(new_namespace)
+    }
+}
+
+/// The directory of the package in the repository, which must stay inside it
+fn package_subdir(workdir: &Path, path: &str) -> Result<PathBuf> {
+    let is_relative = Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    let package_dir = workdir.join(path);
+    // Symlinks in the repository may point outside as well
+    if !is_relative
+        || !package_dir.is_dir()
+        || !package_dir
+            .canonicalize()?
+            .starts_with(workdir.canonicalize()?)
+    {
+        bail!(Error::InvalidPackagePath(path.to_owned()));
     }
+    Ok(package_dir)
+}
+
+/// Where the package to publish comes from
+#[derive(Debug)]
+pub enum PublishSource {
+    /// A git repository, optionally checked out at a ref, with the package in
+    /// a subdirectory if `path` is given
+    Remote {
+        url: String,
+        refname: Option<String>,
+        path: Option<String>,
+    },
+    /// A directory on the local file system
+    Local(PathBuf),
+}
+
+/// A side effect made by publish, which is recorded in the job of the publish
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SideEffect {
+    /// The tarball has been uploaded to store
+    Upload(PackageName, Version),
+    /// The entry has been added to index
+    IndexEntry(PackageName, Version),
+    /// The branch proposing the entry has been pushed to index
+    Branch(String),
+    /// The pull request of the branch has been opened
+    PullRequest(i64),
+    /// The package has been inserted into database
+    DatabaseRow(PackageName, Version),
+    /// The user has become the owner of the new namespace
+    NamespaceOwner(String, i64),
+    /// The package and its publisher to insert into database once the pull
+    /// request is merged
+    Proposal(database::Package, database::User),
 }
 
 #[derive(Debug)]
 pub struct PublishState {
     pub step: PublishStep,
     pub remote_url: String,
+    /// Directory of the package in the repository, if not at the root
+    pub path: Option<String>,
     pub name: Option<(PackageName, Version)>,
+    /// Url of the pull request to the index, if publishing by pull requests
+    pub pull_request: Option<String>,
     pub error: Option<String>,
 }
 
-#[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PublishStep {
     Block,
     Pull,
     Verify,
     Upload,
     UpdateIndex,
+    Review,
     Done,
 }
 
@@ -196,6 +705,9 @@ impl CommentReport for PublishState {
         } else {
             if self.step >= PublishStep::Pull {
                 body += "- 🚢 Pulling repository\n";
+                if let Some(path) = &self.path {
+                    writeln!(body, "  - 📂 Package in `{}`", path).unwrap();
+                }
             }
             if self.step >= PublishStep::Verify {
                 body += "- 🏭 Verifying package\n";
@@ -206,6 +718,11 @@ impl CommentReport for PublishState {
             if self.step >= PublishStep::UpdateIndex {
                 body += "- 📜 Updating index\n";
             }
+            if self.step >= PublishStep::Review {
+                if let Some(pull_request) = &self.pull_request {
+                    writeln!(body, "- 🔍 Waiting for review of {}", pull_request).unwrap();
+                }
+            }
             if self.step >= PublishStep::Done {
                 body += "- ✔️ Done\n";
             }
@@ -229,8 +746,108 @@ impl CommentReport for PublishState {
                     self.name.as_ref().unwrap().0,
                     self.name.as_ref().unwrap().1
                 ),
+                PublishStep::Review => {
+                    "Publish will finish once the pull request is merged.".to_owned()
+                }
                 _ => "Publish process will finish in minutes.".to_owned(),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix;
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::github::fake::FakeGithub;
+    use crate::testing::{self, BOT_NAME, ROOT};
+
+    async fn open_pull_request(github: &FakeGithub) -> i64 {
+        let branch = "publish/test/pkg-0.1.0";
+        testing::create_branch(&ROOT.join("index.git"), branch, "main");
+        github
+            .create_pull_request(branch, "main", "", "")
+            .await
+            .unwrap()
+            .number
+    }
+
+    #[tokio::test]
+    async fn test_wait_pull_request_merge() {
+        let _lock = testing::lock();
+        let github = Arc::new(FakeGithub::new(BOT_NAME));
+        let controller = Controller::with_github(github.clone()).unwrap();
+
+        let number = open_pull_request(&github).await;
+        github.approve_pull_request(number);
+        controller.wait_pull_request(number).await.unwrap();
+        assert!(github.pull_request(number).await.unwrap().merged);
+    }
+
+    #[tokio::test]
+    async fn test_wait_pull_request_checks_failed() {
+        let _lock = testing::lock();
+        let github = Arc::new(FakeGithub::new(BOT_NAME));
+        let controller = Controller::with_github(github.clone()).unwrap();
+
+        let number = open_pull_request(&github).await;
+        github.approve_pull_request(number);
+        github.set_checks(CheckState::Failure);
+        let error = controller.wait_pull_request(number).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            Error::PullRequestChecksFailed(number).to_string()
+        );
+        assert!(!github.pull_request(number).await.unwrap().merged);
+    }
+
+    #[tokio::test]
+    async fn test_wait_pull_request_closed() {
+        let _lock = testing::lock();
+        let github = Arc::new(FakeGithub::new(BOT_NAME));
+        let controller = Controller::with_github(github.clone()).unwrap();
+
+        let number = open_pull_request(&github).await;
+        github.close_pull_request(number).await.unwrap();
+        let error = controller.wait_pull_request(number).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            Error::PullRequestClosed(number).to_string()
+        );
+    }
+
+    #[test]
+    fn test_package_subdir() {
+        let root = TempDir::new("elba-bot-subdir").unwrap();
+        let workdir = root.path().join("repo");
+        let outside = root.path().join("outside");
+        fs::create_dir_all(workdir.join("packages").join("pkg")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        unix::fs::symlink(&outside, workdir.join("escape")).unwrap();
+
+        assert_eq!(
+            package_subdir(&workdir, "packages/pkg").unwrap(),
+            workdir.join("packages").join("pkg")
+        );
+
+        let outside = outside.display().to_string();
+        let invalid = [
+            "..",
+            "packages/../..",
+            outside.as_str(),
+            "escape",
+            "packages/missing",
+        ];
+        for path in &invalid {
+            let error = package_subdir(&workdir, path).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                Error::InvalidPackagePath(path.to_string()).to_string()
+            );
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt::Write;
+
+use super::*;
+use crate::error::Result;
+use crate::github::{Comment, RateLimit};
+
+impl Controller {
+    /// Reply with the health of the bot
+    pub async fn status(&self, comment: Comment) -> Result<()> {
+        let rate_limit = self.github.rate_limit().await;
+        let unfinished_jobs = self
+            .database
+            .lock()
+            .await
+            .query_unfinished_jobs()?
+            .iter()
+            .filter(|job| job.comment_id != comment.id)
+            .count();
+        let state = StatusState {
+            rate_limit,
+            unfinished_jobs,
+        };
+        if let Some(rate_limit) = &state.rate_limit {
+            info!("Github rate limit: {}", rate_limit);
+        }
+
+        self.finish_job(&comment, &state, None).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct StatusState {
+    pub rate_limit: Option<RateLimit>,
+    pub unfinished_jobs: usize,
+}
+
+impl CommentReport for StatusState {
+    fn render_title(&self, _: &Comment) -> Option<&str> {
+        Some("Status")
+    }
+
+    fn render_body(&self, _: &Comment) -> Option<String> {
+        let mut body = String::new();
+
+        match &self.rate_limit {
+            Some(rate_limit) => write!(body, "- 📊 Github API: {}\n", rate_limit).unwrap(),
+            None => body += "- 📊 Github API: rate limit unknown\n",
+        }
+        write!(body, "- 🗂️ Unfinished commands: {}\n", self.unfinished_jobs).unwrap();
+
+        Some(body)
+    }
+
+    fn render_msg(&self, _: &Comment) -> String {
+        "elba-bot is up and running.".to_owned()
+    }
+}
@@ -0,0 +1,811 @@
+//! End-to-end tests driving the controller with a fake Github, and local bare
+//! repositories as the index and the store
+
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use git2::Repository;
+use hmac::{Hmac, Mac};
+use semver::Version;
+use sha2::Sha256;
+
+use super::*;
+use crate::github::fake::{self, FakeGithub};
+use crate::github::Reaction;
+use crate::testing::{self, BOT_NAME, ROOT};
+
+/// Wait for the bot to finish the command in the comment and return the report
+async fn wait_for_report(github: &FakeGithub, comment_id: i64, finished: &[&str]) -> String {
+    let deadline = Instant::now() + Duration::from_secs(60);
+    loop {
+        let body = github.comment_body(comment_id).unwrap();
+        if finished.iter().any(|msg| body.contains(msg)) {
+            return body;
+        }
+        assert!(Instant::now() < deadline, "command not finished: {}", body);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+}
+
+/// Wait for the bot to react to the comment with only the reaction
+async fn wait_for_reaction(github: &FakeGithub, comment_id: i64, reaction: Reaction) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while github.reactions(comment_id) != [reaction] {
+        assert!(
+            Instant::now() < deadline,
+            "expected {:?} but reactions are {:?}",
+            reaction,
+            github.reactions(comment_id)
+        );
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_comment_commands() {
+    let _lock = testing::lock();
+    let root = &*ROOT;
+
+    let github = Arc::new(FakeGithub::new(BOT_NAME));
+    let alice = github.add_user("alice");
+    let bob = github.add_user("bob");
+
+    let controller = Arc::new(Controller::with_github(github.clone()).unwrap());
+    tokio::spawn(controller.run(None));
+
+    // Wait for the bot to start polling, since comments made before are ignored
+    while github.list_count() < 2 {
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+
+    // Unknown command
+    let comment_id = github.post_comment(&alice, &format!("@{} /frobnicate", BOT_NAME));
+    wait_for_report(&github, comment_id, &["was not able to understand"]).await;
+    wait_for_reaction(&github, comment_id, Reaction::Confused).await;
+
+    // Publish a package into a new namespace
+    let comment_id = github.post_comment(
+        &alice,
+        &format!(
+            "@{} /publish file://{}",
+            BOT_NAME,
+            root.join("pkg").display()
+        ),
+    );
+    let report = wait_for_report(&github, comment_id, &["has been published", "failed"]).await;
+    assert!(report.contains("has been published"), "{}", report);
+    wait_for_reaction(&github, comment_id, Reaction::Rocket).await;
+
+    let database = Database::open(&CONFIG.db_path).unwrap();
+    let packages = database.query_package(Some("test")).unwrap();
+    assert_eq!(packages.len(), 1);
+    assert_eq!(packages[0].name, "pkg");
+    assert_eq!(packages[0].user_id, alice.id);
+    let owners = database.query_namespace_owners("test").unwrap();
+    assert_eq!(owners.len(), 1);
+    assert_eq!(owners[0].id, alice.id);
+    let metafile = fs::read_to_string(root.join("index").join("test").join("pkg")).unwrap();
+    assert!(metafile.contains("0.1.0"));
+    let readme = fs::read_to_string(root.join("index").join("README.md")).unwrap();
+    assert!(readme.contains("test/pkg 0.1.0"));
+    assert!(root
+        .join("store")
+        .join("test")
+        .join("pkg")
+        .join("test_pkg_0.1.0.tar.gz")
+        .exists());
+
+    // The same version can't be published again
+    let comment_id = github.post_comment(
+        &alice,
+        &format!(
+            "@{} /publish file://{}",
+            BOT_NAME,
+            root.join("pkg").display()
+        ),
+    );
+    let report = wait_for_report(&github, comment_id, &["has been published", "failed"]).await;
+    assert!(report.contains("failed"), "{}", report);
+    wait_for_reaction(&github, comment_id, Reaction::ThumbsDown).await;
+
+    // Only the owners of the namespace can yank
+    let comment_id = github.post_comment(&bob, &format!("@{} /yank test/pkg 0.1.0", BOT_NAME));
+    let report = wait_for_report(&github, comment_id, &["has been yanked", "failed"]).await;
+    assert!(report.contains("failed"), "{}", report);
+
+    let comment_id = github.post_comment(&alice, &format!("@{} /yank test/pkg 0.1.0", BOT_NAME));
+    let report = wait_for_report(&github, comment_id, &["has been yanked", "failed"]).await;
+    assert!(report.contains("has been yanked"), "{}", report);
+    let packages = database.query_package(Some("test")).unwrap();
+    assert!(packages[0].yanked);
+    let metafile = fs::read_to_string(root.join("index").join("test").join("pkg")).unwrap();
+    assert!(metafile.contains("\"yanked\":true"));
+    let readme = fs::read_to_string(root.join("index").join("README.md")).unwrap();
+    assert!(
+        readme.contains("~~`test/pkg 0.1.0`~~ *(yanked)*"),
+        "{}",
+        readme
+    );
+
+    // Yanking again changes nothing in the index
+    let index = Repository::open_bare(root.join("index.git")).unwrap();
+    let head = index.refname_to_id("refs/heads/main").unwrap();
+    let comment_id = github.post_comment(&alice, &format!("@{} /yank test/pkg 0.1.0", BOT_NAME));
+    let report = wait_for_report(&github, comment_id, &["has been yanked", "failed"]).await;
+    assert!(report.contains("has been yanked"), "{}", report);
+    assert_eq!(index.refname_to_id("refs/heads/main").unwrap(), head);
+
+    // A publish of a repository which can't be cloned fails
+    let comment_id = github.post_comment(
+        &alice,
+        &format!(
+            "@{} /publish file://{}",
+            BOT_NAME,
+            root.join("nonexistent").display()
+        ),
+    );
+    let report = wait_for_report(&github, comment_id, &["has been published", "failed"]).await;
+    assert!(report.contains("failed"), "{}", report);
+    wait_for_reaction(&github, comment_id, Reaction::ThumbsDown).await;
+
+    // Owners can add and remove other owners
+    let comment_id = github.post_comment(&alice, &format!("@{} /add-owner test @bob", BOT_NAME));
+    let report = wait_for_report(&github, comment_id, &["is now an owner", "failed"]).await;
+    assert!(
+        report.contains("@bob is now an owner of namespace `test`."),
+        "{}",
+        report
+    );
+    let owners = database.query_namespace_owners("test").unwrap();
+    assert_eq!(owners.len(), 2);
+
+    let comment_id = github.post_comment(&alice, &format!("@{} /remove-owner test @bob", BOT_NAME));
+    let report = wait_for_report(&github, comment_id, &["is no longer an owner", "failed"]).await;
+    assert!(
+        report.contains("@bob is no longer an owner of namespace `test`."),
+        "{}",
+        report
+    );
+    let owners = database.query_namespace_owners("test").unwrap();
+    assert_eq!(owners.len(), 1);
+    assert_eq!(owners[0].id, alice.id);
+
+    // A namespace is transferred once the recipient accepts it
+    let comment_id = github.post_comment(
+        &alice,
+        &format!("@{} /transfer-namespace test @bob", BOT_NAME),
+    );
+    let report = wait_for_report(&github, comment_id, &["will be transferred", "failed"]).await;
+    assert!(
+        report.contains("Namespace `test` will be transferred to @bob"),
+        "{}",
+        report
+    );
+    let owners = database.query_namespace_owners("test").unwrap();
+    assert_eq!(owners.len(), 1);
+    assert_eq!(owners[0].id, alice.id);
+
+    let comment_id = github.post_comment(&bob, &format!("@{} /accept-transfer test", BOT_NAME));
+    let report = wait_for_report(&github, comment_id, &["has been transferred", "failed"]).await;
+    assert!(
+        report.contains("Namespace `test` has been transferred from @alice to @bob."),
+        "{}",
+        report
+    );
+    let owners = database.query_namespace_owners("test").unwrap();
+    assert_eq!(owners.len(), 1);
+    assert_eq!(owners[0].id, bob.id);
+
+    // Status
+    let comment_id = github.post_comment(&alice, &format!("@{} /status", BOT_NAME));
+    let report = wait_for_report(&github, comment_id, &["up and running"]).await;
+    assert!(report.contains("elba-bot is up and running."), "{}", report);
+
+    // Comments of the bot itself are not commands
+    let comment_id = github.post_comment(
+        &github::User {
+            id: github.viewer_id(),
+            name: BOT_NAME.to_owned(),
+        },
+        &format!("@{} /frobnicate", BOT_NAME),
+    );
+    tokio::time::delay_for(Duration::from_secs(1)).await;
+    assert_eq!(
+        github.comment_body(comment_id).unwrap(),
+        format!("@{} /frobnicate", BOT_NAME)
+    );
+}
+
+/// Record the publish requested by the user as interrupted at the step with
+/// the side effects, returning the comment of it
+fn insert_publish_job(
+    github: &FakeGithub,
+    database: &Database,
+    user: &github::User,
+    url: &str,
+    step: PublishStep,
+    effects: &[publish::SideEffect],
+) -> Comment {
+    let comment = github.push_comment(
+        fake::ISSUE_NUMBER,
+        user,
+        &format!("@{} /publish {}", BOT_NAME, url),
+    );
+    database
+        .insert_user(database::User {
+            id: user.id,
+            name: user.name.clone(),
+        })
+        .unwrap();
+    database
+        .insert_comment(database::Comment {
+            id: comment.id,
+            user_id: user.id,
+            body: comment.body.clone(),
+            created_at: comment.created_at,
+            issue_number: Some(comment.issue_number),
+        })
+        .unwrap();
+    database
+        .insert_job(database::Job {
+            comment_id: comment.id,
+            command: serde_json::to_string(&Command::Publish {
+                git: url.to_owned(),
+                refname: None,
+                path: None,
+            })
+            .unwrap(),
+            step: Some(serde_json::to_string(&step).unwrap()),
+            effects: Some(serde_json::to_string(effects).unwrap()),
+            error: None,
+            finished: false,
+        })
+        .unwrap();
+    comment
+}
+
+/// Assert that nothing of the package is left in the store, the index and the
+/// database
+fn assert_not_published(database: &Database, group: &str, name: &str, version: &str) {
+    let root = &*ROOT;
+    assert!(!root
+        .join("store")
+        .join(group)
+        .join(name)
+        .join(format!("{}_{}_{}.tar.gz", group, name, version))
+        .exists());
+    assert!(!root.join("index").join(group).join(name).exists());
+    let readme = fs::read_to_string(root.join("index").join("README.md")).unwrap();
+    assert!(
+        !readme.contains(&format!("{}/{}", group, name)),
+        "{}",
+        readme
+    );
+    assert!(database.query_package(Some(group)).unwrap().is_empty());
+    assert!(database.query_namespace_owners(group).unwrap().is_empty());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_publish_rollback() {
+    let _lock = testing::lock();
+    let package_dir = ROOT.join("rollback");
+    testing::init_package_repo(&package_dir, "rollback/pkg", "0.1.0");
+    let url = format!("file://{}", package_dir.display());
+
+    let github = Arc::new(FakeGithub::new(BOT_NAME));
+    let alice = github.add_user("alice");
+    let controller = Arc::new(Controller::with_github(github.clone()).unwrap());
+    let database = Database::open(&CONFIG.db_path).unwrap();
+
+    // Publishing into a new namespace uploads the tarball, inserts the package
+    // and the owner, then adds the index entry
+    for effects in 1..=4 {
+        *controller.fail_publish_after.lock().unwrap() = Some(effects);
+        let comment = github.push_comment(
+            fake::ISSUE_NUMBER,
+            &alice,
+            &format!("@{} /publish {}", BOT_NAME, url),
+        );
+        let publish = {
+            let controller = controller.clone();
+            let url = url.clone();
+            let comment = comment.clone();
+            async move { controller.publish(url, None, None, comment).await }
+        };
+        tokio::spawn(publish).await.unwrap().unwrap();
+
+        let report = github.comment_body(comment.id).unwrap();
+        assert!(report.contains("failed"), "{}", report);
+        assert_not_published(&database, "rollback", "pkg", "0.1.0");
+    }
+
+    // Nothing left behind stops publishing it again
+    *controller.fail_publish_after.lock().unwrap() = None;
+    let comment = github.push_comment(
+        fake::ISSUE_NUMBER,
+        &alice,
+        &format!("@{} /publish {}", BOT_NAME, url),
+    );
+    let publish = {
+        let controller = controller.clone();
+        let comment = comment.clone();
+        async move { controller.publish(url, None, None, comment).await }
+    };
+    tokio::spawn(publish).await.unwrap().unwrap();
+    let report = github.comment_body(comment.id).unwrap();
+    assert!(report.contains("has been published"), "{}", report);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_resume_interrupted_publish() {
+    let _lock = testing::lock();
+    let package_dir = ROOT.join("interrupted");
+    testing::init_package_repo(&package_dir, "interrupted/pkg", "0.1.0");
+    let url = format!("file://{}", package_dir.display());
+
+    let github = Arc::new(FakeGithub::new(BOT_NAME));
+    let alice = github.add_user("alice");
+    let controller = Arc::new(Controller::with_github(github.clone()).unwrap());
+    let database = Database::open(&CONFIG.db_path).unwrap();
+    let publisher = database::User {
+        id: alice.id,
+        name: alice.name.clone(),
+    };
+
+    // The bot stopped after uploading the tarball and inserting the package
+    let (tarball, manifest) = elba::cli::index::package(&package_dir).unwrap();
+    let name = manifest.package.name.clone();
+    let version = manifest.package.version.clone();
+    controller
+        .workspace
+        .lock()
+        .await
+        .store
+        .upload_package(&manifest, &tarball, &publisher)
+        .unwrap();
+    database
+        .insert_package(database::Package {
+            group: "interrupted".to_owned(),
+            name: "pkg".to_owned(),
+            version: version.clone(),
+            description: None,
+            homepage: None,
+            repository: None,
+            user_id: alice.id,
+            yanked: false,
+            path: None,
+        })
+        .unwrap();
+
+    let effects = vec![
+        publish::SideEffect::Upload(name.clone(), version.clone()),
+        publish::SideEffect::DatabaseRow(name, version),
+    ];
+    let comment = insert_publish_job(
+        &github,
+        &database,
+        &alice,
+        &url,
+        PublishStep::UpdateIndex,
+        &effects,
+    );
+
+    controller.resume_jobs().await.unwrap();
+
+    let report = github.comment_body(comment.id).unwrap();
+    assert!(report.contains("failed"), "{}", report);
+    assert_not_published(&database, "interrupted", "pkg", "0.1.0");
+    assert!(database
+        .query_unfinished_jobs()
+        .unwrap()
+        .iter()
+        .all(|job| job.comment_id != comment.id));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_resume_review() {
+    let _lock = testing::lock();
+    let github = Arc::new(FakeGithub::new(BOT_NAME));
+    let alice = github.add_user("alice");
+    let mut controller = Controller::with_github(github.clone()).unwrap();
+    controller.publish_mode = IndexPublishMode::PullRequest;
+    let controller = Arc::new(controller);
+    let database = Database::open(&CONFIG.db_path).unwrap();
+
+    let publisher = database::User {
+        id: alice.id,
+        name: alice.name.clone(),
+    };
+
+    // The bot stopped while waiting for the pull requests of two publishes,
+    // which leave the package to insert until they are merged
+    let mut jobs = Vec::new();
+    for group in &["merged", "closed"] {
+        let package_dir = ROOT.join(format!("review-{}", group));
+        testing::init_package_repo(&package_dir, &format!("{}/pkg", group), "0.1.0");
+        let (tarball, manifest) = elba::cli::index::package(&package_dir).unwrap();
+        let name = manifest.package.name.clone();
+        let version = manifest.package.version.clone();
+        controller
+            .workspace
+            .lock()
+            .await
+            .store
+            .upload_package(&manifest, &tarball, &publisher)
+            .unwrap();
+
+        let package = database::Package {
+            group: group.to_string(),
+            name: "pkg".to_owned(),
+            version: version.clone(),
+            description: None,
+            homepage: None,
+            repository: None,
+            user_id: alice.id,
+            yanked: false,
+            path: None,
+        };
+        let branch = format!("publish/{}/pkg-0.1.0", group);
+        testing::create_branch(&ROOT.join("index.git"), &branch, "main");
+        let pull_request = github
+            .create_pull_request(&branch, "main", "", "")
+            .await
+            .unwrap();
+        let effects = vec![
+            publish::SideEffect::Upload(name, version),
+            publish::SideEffect::Proposal(package, publisher.clone()),
+            publish::SideEffect::Branch(branch),
+            publish::SideEffect::PullRequest(pull_request.number),
+        ];
+        let comment = insert_publish_job(
+            &github,
+            &database,
+            &alice,
+            &format!("file://{}", package_dir.display()),
+            PublishStep::Review,
+            &effects,
+        );
+        jobs.push((comment, pull_request.number));
+    }
+    assert!(database.query_package(Some("merged")).unwrap().is_empty());
+
+    // A maintainer approves the first, which the bot merges, and closes the
+    // second, so that publish is rolled back
+    github.approve_pull_request(jobs[0].1);
+    github.close_pull_request(jobs[1].1).await.unwrap();
+    controller.resume_jobs().await.unwrap();
+
+    let (comment, number) = &jobs[0];
+    let report = wait_for_report(&github, comment.id, &["has been published", "failed"]).await;
+    assert!(report.contains("has been published"), "{}", report);
+    assert!(github.pull_request(*number).await.unwrap().merged);
+    assert_eq!(database.query_package(Some("merged")).unwrap().len(), 1);
+
+    let (comment, _) = &jobs[1];
+    let report = wait_for_report(&github, comment.id, &["has been published", "failed"]).await;
+    assert!(report.contains("failed"), "{}", report);
+    assert_not_published(&database, "closed", "pkg", "0.1.0");
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_expired_command() {
+    let _lock = testing::lock();
+    let github = Arc::new(FakeGithub::new(BOT_NAME));
+    let alice = github.add_user("alice");
+    let controller = Arc::new(Controller::with_github(github.clone()).unwrap());
+
+    // A command posted longer ago than the bot catches up with
+    let comment = github.push_comment(
+        fake::ISSUE_NUMBER,
+        &alice,
+        &format!("@{} /publish file:///expired", BOT_NAME),
+    );
+    let comment = Comment {
+        created_at: comment.created_at - chrono::Duration::hours(CONFIG.command_max_age_hours + 1),
+        ..comment
+    };
+    controller.handle_comment(comment.clone()).await.unwrap();
+
+    let report = github.comment_body(comment.id).unwrap();
+    assert!(report.contains("Command Expired"), "{}", report);
+    assert!(report.contains("please comment again"), "{}", report);
+    assert_eq!(github.reactions(comment.id), [Reaction::ThumbsDown]);
+    // The command is not executed
+    let database = Database::open(&CONFIG.db_path).unwrap();
+    assert!(database.query_job(comment.id).unwrap().is_none());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_labelled_issues() {
+    let _lock = testing::lock();
+    let github = Arc::new(FakeGithub::new(BOT_NAME));
+    let alice = github.add_user("alice");
+    let controller = Arc::new(Controller::with_github(github.clone()).unwrap());
+    let database = Database::open(&CONFIG.db_path).unwrap();
+    let label = CONFIG.index_issue_label.as_deref().unwrap();
+    let body = format!("@{} /status", BOT_NAME);
+
+    // An issue carrying the label accepts commands
+    github.add_label(2, "bug");
+    github.add_label(2, label);
+    let comment = github.push_comment(2, &alice, &body);
+    controller.handle_comment(comment.clone()).await.unwrap();
+    let report = wait_for_report(&github, comment.id, &["up and running"]).await;
+    assert!(report.contains("elba-bot is up and running."), "{}", report);
+
+    // Others don't
+    let comment = github.push_comment(3, &alice, &body);
+    controller.handle_comment(comment.clone()).await.unwrap();
+    assert!(database.query_comment(comment.id).unwrap().is_none());
+    assert_eq!(github.comment_body(comment.id).unwrap(), body);
+    assert!(github.reactions(comment.id).is_empty());
+
+    // Until the label is added
+    github.add_label(3, label);
+    let comment = github.push_comment(3, &alice, &body);
+    controller.handle_comment(comment.clone()).await.unwrap();
+    let report = wait_for_report(&github, comment.id, &["up and running"]).await;
+    assert!(report.contains("elba-bot is up and running."), "{}", report);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_webhook_delivery() {
+    let _lock = testing::lock();
+    let secret = "It's a Secret to Everybody";
+    let github = Arc::new(FakeGithub::new(BOT_NAME));
+    let alice = github.add_user("alice");
+    let controller = Arc::new(Controller::with_github(github.clone()).unwrap());
+    let database = Database::open(&CONFIG.db_path).unwrap();
+    let catching_up = database.query_last_comment().unwrap().is_some();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(controller.run_webhook(listener, secret.to_owned()));
+
+    // Wait for the catch up with comments missed while down, so that the
+    // command is only delivered by the webhook
+    while catching_up && github.list_count() < 1 {
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+
+    let comment = github.push_comment(
+        fake::ISSUE_NUMBER,
+        &alice,
+        &format!("@{} /status", BOT_NAME),
+    );
+    let payload = serde_json::json!({
+        "action": "created",
+        "comment": {
+            "id": comment.id,
+            "user": { "id": alice.id, "login": alice.name },
+            "body": comment.body,
+            "created_at": comment.created_at.to_rfc3339(),
+            "issue_url": format!(
+                "https://api.github.com/repos/elba/index/issues/{}",
+                comment.issue_number
+            ),
+        },
+    })
+    .to_string();
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+    mac.input(payload.as_bytes());
+    let signature = format!("sha256={}", hex::encode(mac.result().code()));
+
+    let client = reqwest::Client::new();
+    let deliver = |signature: &str| {
+        client
+            .post(&url)
+            .header("X-GitHub-Event", "issue_comment")
+            .header("X-Hub-Signature-256", signature)
+            .body(payload.clone())
+            .send()
+    };
+
+    // A delivery which is not signed with the secret is rejected
+    let resp = deliver("sha256=00").await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    tokio::time::delay_for(Duration::from_secs(1)).await;
+    assert!(database.query_comment(comment.id).unwrap().is_none());
+
+    let resp = deliver(&signature).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let report = wait_for_report(&github, comment.id, &["up and running"]).await;
+    assert!(report.contains("elba-bot is up and running."), "{}", report);
+    wait_for_reaction(&github, comment.id, Reaction::Rocket).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_reply_mode() {
+    let _lock = testing::lock();
+    let package_dir = ROOT.join("reply");
+    testing::init_package_repo(&package_dir, "reply/pkg", "0.1.0");
+
+    let github = Arc::new(FakeGithub::new(BOT_NAME));
+    let alice = github.add_user("alice");
+    let mut controller = Controller::with_github(github.clone()).unwrap();
+    controller.report_mode = ReportMode::Reply;
+    let controller = Arc::new(controller);
+    let database = Database::open(&CONFIG.db_path).unwrap();
+
+    let body = format!("@{} /publish file://{}", BOT_NAME, package_dir.display());
+    let comment = github.push_comment(fake::ISSUE_NUMBER, &alice, &body);
+    controller.handle_comment(comment.clone()).await.unwrap();
+
+    // The bot replies quoting the command, and keeps editing the reply as the
+    // publish goes on
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let reply_id = loop {
+        if let Some(reply_id) = database.query_reply(comment.id).unwrap() {
+            break reply_id;
+        }
+        assert!(Instant::now() < deadline, "no reply to the command");
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    };
+    let report = wait_for_report(&github, reply_id, &["has been published", "failed"]).await;
+    assert!(report.starts_with(&format!("> {}\n", body)), "{}", report);
+    assert!(report.contains("has been published"), "{}", report);
+
+    let replies: Vec<_> = github
+        .comments()
+        .into_iter()
+        .filter(|reply| reply.user.id == github.viewer_id())
+        .filter(|reply| reply.body.contains(&body))
+        .collect();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].id, reply_id);
+    // The command itself is left alone
+    assert_eq!(github.comment_body(comment.id).unwrap(), body);
+    wait_for_reaction(&github, comment.id, Reaction::Rocket).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_publish_single_index_commit() {
+    let _lock = testing::lock();
+    let package_dir = ROOT.join("single");
+    testing::init_package_repo(&package_dir, "single/pkg", "0.1.0");
+    let url = format!("file://{}", package_dir.display());
+
+    let github = Arc::new(FakeGithub::new(BOT_NAME));
+    let alice = github.add_user("alice");
+    let controller = Arc::new(Controller::with_github(github.clone()).unwrap());
+    let index = Repository::open_bare(ROOT.join("index.git")).unwrap();
+    let head_before = index.refname_to_id("refs/heads/main").unwrap();
+
+    let comment = github.push_comment(
+        fake::ISSUE_NUMBER,
+        &alice,
+        &format!("@{} /publish {}", BOT_NAME, url),
+    );
+    let publish = {
+        let controller = controller.clone();
+        let comment = comment.clone();
+        async move { controller.publish(url, None, None, comment).await }
+    };
+    tokio::spawn(publish).await.unwrap().unwrap();
+    let report = github.comment_body(comment.id).unwrap();
+    assert!(report.contains("has been published"), "{}", report);
+
+    // The entry and the README are added by a single commit
+    let head = index
+        .find_reference("refs/heads/main")
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    assert_eq!(head.parent_count(), 1);
+    assert_eq!(head.parent_id(0).unwrap(), head_before);
+    assert!(
+        head.message().unwrap().contains("Published-by"),
+        "{}",
+        head.message().unwrap()
+    );
+    let tree = head.tree().unwrap();
+    let diff = index
+        .diff_tree_to_tree(
+            Some(&head.parent(0).unwrap().tree().unwrap()),
+            Some(&tree),
+            None,
+        )
+        .unwrap();
+    let mut paths: Vec<_> = diff
+        .deltas()
+        .map(|delta| delta.new_file().path().unwrap().to_owned())
+        .collect();
+    paths.sort();
+    assert_eq!(paths, [Path::new("README.md"), Path::new("single/pkg")]);
+    let readme = tree
+        .get_path(Path::new("README.md"))
+        .unwrap()
+        .to_object(&index)
+        .unwrap()
+        .peel_to_blob()
+        .unwrap();
+    assert!(String::from_utf8_lossy(readme.content()).contains("single/pkg 0.1.0"));
+}
+
+/// Content of the file on the index branch of the bare index repository
+fn index_file(path: &str) -> Option<String> {
+    let index = Repository::open_bare(ROOT.join("index.git")).unwrap();
+    let tree = index
+        .find_reference("refs/heads/main")
+        .unwrap()
+        .peel_to_tree()
+        .unwrap();
+    let entry = tree.get_path(Path::new(path)).ok()?;
+    let blob = entry.to_object(&index).unwrap().peel_to_blob().unwrap();
+    Some(String::from_utf8_lossy(blob.content()).into_owned())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_publish_pull_requests() {
+    let _lock = testing::lock();
+    let github = Arc::new(FakeGithub::new(BOT_NAME));
+    let alice = github.add_user("alice");
+    let mut controller = Controller::with_github(github.clone()).unwrap();
+    controller.publish_mode = IndexPublishMode::PullRequest;
+    let controller = Arc::new(controller);
+    let database = Database::open(&CONFIG.db_path).unwrap();
+
+    // Two publishes wait for their pull requests at the same time
+    let mut comments = Vec::new();
+    for name in &["alpha", "beta"] {
+        let package_dir = ROOT.join(format!("proposed-{}", name));
+        testing::init_package_repo(&package_dir, &format!("proposed/{}", name), "0.1.0");
+        let url = format!("file://{}", package_dir.display());
+        let comment = github.push_comment(
+            fake::ISSUE_NUMBER,
+            &alice,
+            &format!("@{} /publish {}", BOT_NAME, url),
+        );
+        let publish = {
+            let controller = controller.clone();
+            let comment = comment.clone();
+            async move { controller.publish(url, None, None, comment).await }
+        };
+        tokio::spawn(publish);
+        comments.push(comment);
+    }
+    for comment in &comments {
+        let report = wait_for_report(&github, comment.id, &["Waiting for review", "failed"]).await;
+        assert!(report.contains("Waiting for review"), "{}", report);
+    }
+
+    // Nothing lists the packages before they are merged
+    assert!(database.query_package(Some("proposed")).unwrap().is_empty());
+    assert!(!index_file("README.md")
+        .unwrap_or_default()
+        .contains("proposed/"));
+
+    // Both merge, since neither touches the README
+    for name in &["alpha", "beta"] {
+        let branch = format!("publish/proposed/{}-0.1.0", name);
+        let pull_request = github.branch_pull_request(&branch).await.unwrap().unwrap();
+        github.approve_pull_request(pull_request.number);
+    }
+    for comment in &comments {
+        let report = wait_for_report(&github, comment.id, &["has been published", "failed"]).await;
+        assert!(report.contains("has been published"), "{}", report);
+    }
+    assert!(index_file("proposed/alpha").is_some());
+    assert!(index_file("proposed/beta").is_some());
+    assert_eq!(database.query_package(Some("proposed")).unwrap().len(), 2);
+
+    // The README follows by its own pull request
+    assert!(!index_file("README.md")
+        .unwrap_or_default()
+        .contains("proposed/"));
+    let pull_request = github
+        .branch_pull_request(README_BRANCH)
+        .await
+        .unwrap()
+        .unwrap();
+    github
+        .merge_pull_request(pull_request.number, &pull_request.head.sha)
+        .await
+        .unwrap();
+    let readme = index_file("README.md").unwrap();
+    assert!(readme.contains("proposed/alpha 0.1.0"), "{}", readme);
+    assert!(readme.contains("proposed/beta 0.1.0"), "{}", readme);
+}
@@ -0,0 +1,156 @@
+use std::fmt::Write;
+
+use super::*;
+use crate::error::{Error, Result};
+use crate::github::Comment;
+
+impl Controller {
+    /// Open a namespace transfer, which will complete once the recipient accepts it
+    pub async fn transfer_namespace(
+        &self,
+        group: String,
+        user_name: String,
+        comment: Comment,
+    ) -> Result<()> {
+        let mut state = TransferState {
+            group: group.clone(),
+            from: comment.user.name.clone(),
+            to: user_name.clone(),
+            step: TransferStep::Pending,
+            error: None,
+        };
+
+        let res: Result<()> = try {
+            self.check_namespace_owner(&group, &comment.user).await?;
+
+            let user = self.github.query_user(&user_name).await?;
+            state.to = user.name.clone();
+
+            let database = self.database.lock().await;
+            database.insert_user(database::User {
+                id: user.id,
+                name: user.name.clone(),
+            })?;
+            database.insert_transfer(database::Transfer {
+                group: group.clone(),
+                from_user_id: comment.user.id,
+                to_user_id: user.id,
+            })?;
+
+            ()
+        };
+
+        self.report_transfer(&comment, state, res).await
+    }
+
+    /// Complete the pending namespace transfer to the commenter
+    pub async fn accept_transfer(&self, group: String, comment: Comment) -> Result<()> {
+        let mut state = TransferState {
+            group: group.clone(),
+            from: String::new(),
+            to: comment.user.name.clone(),
+            step: TransferStep::Accepted,
+            error: None,
+        };
+
+        let res: Result<()> = try {
+            let database = self.database.lock().await;
+            let transfer = database
+                .query_transfer(&group)?
+                .filter(|transfer| transfer.to_user_id == comment.user.id)
+                .ok_or(Error::NoPendingTransfer {
+                    group: group.clone(),
+                })?;
+            state.from = database.query_user(transfer.from_user_id)?.unwrap().name;
+
+            // The transfer is void if the sender has been removed from owners since
+            let owners = database.query_namespace_owners(&group)?;
+            if !owners.iter().any(|owner| owner.id == transfer.from_user_id) {
+                database.delete_transfer(&group)?;
+                Err(Error::NoPendingTransfer {
+                    group: group.clone(),
+                })?;
+            }
+
+            database.complete_transfer(&transfer)?;
+
+            ()
+        };
+
+        self.report_transfer(&comment, state, res).await
+    }
+
+    async fn report_transfer(
+        &self,
+        comment: &Comment,
+        mut state: TransferState,
+        res: Result<()>,
+    ) -> Result<()> {
+        match res {
+            Ok(()) => info!("Transfer namespace done: {:?}", state),
+            Err(error) => {
+                state.error = Some(error.to_string());
+                info!("Transfer namespace error: {:?}", state);
+            }
+        }
+        self.finish_job(comment, &state, state.error.as_deref())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct TransferState {
+    pub group: String,
+    pub from: String,
+    pub to: String,
+    pub step: TransferStep,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransferStep {
+    /// Waiting for the recipient to accept
+    Pending,
+    /// The recipient has become the owner
+    Accepted,
+}
+
+impl CommentReport for TransferState {
+    fn render_title(&self, _: &Comment) -> Option<&str> {
+        Some("Transfer Namespace")
+    }
+
+    fn render_body(&self, _: &Comment) -> Option<String> {
+        let mut body = String::new();
+
+        if let Some(error) = &self.error {
+            write!(body, "- ❌ *{}*\n\n", error).unwrap();
+        } else {
+            body += "- 📨 Transfer requested\n";
+            if self.step == TransferStep::Accepted {
+                body += "- 🤝 Transfer accepted\n";
+            }
+        }
+
+        Some(body)
+    }
+
+    fn render_msg(&self, _: &Comment) -> String {
+        if let Some(_) = &self.error {
+            "Transfer failed due to the reason above.".to_owned()
+        } else {
+            match self.step {
+                TransferStep::Pending => format!(
+                    "Namespace `{}` will be transferred to @{} once they reply `@{} /accept-transfer {}`.",
+                    self.group, self.to, CONFIG.bot_name, self.group
+                ),
+                TransferStep::Accepted => format!(
+                    "Namespace `{}` has been transferred from @{} to @{}.",
+                    self.group, self.from, self.to
+                ),
+            }
+        }
+    }
+}
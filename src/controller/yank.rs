@@ -0,0 +1,203 @@
+use std::fmt::Write;
+
+use elba::package::Name as PackageName;
+use failure::bail;
+use semver::Version;
+use tokio::task::block_in_place;
+
+use super::*;
+use crate::config::IndexPublishMode;
+use crate::error::{Error, Result};
+use crate::github::Comment;
+
+impl Controller {
+    pub async fn yank(
+        &self,
+        name: PackageName,
+        version: Version,
+        yanked: bool,
+        comment: Comment,
+    ) -> Result<()> {
+        let mut state = YankState {
+            name: name.clone(),
+            version: version.clone(),
+            yanked,
+            done: false,
+            error: None,
+        };
+
+        let res: Result<()> = try {
+            self.check_namespace_owner(name.normalized_group(), &comment.user)
+                .await?;
+            self.yank_package(&name, &version, yanked).await?;
+        };
+
+        match res {
+            Ok(()) => {
+                state.done = true;
+                info!("Yank done: {:?}", state);
+            }
+            Err(error) => {
+                state.error = Some(error.to_string());
+                info!("Yank error: {:?}", state);
+            }
+        }
+        self.finish_job(&comment, &state, state.error.as_deref())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Flip the yanked flag in index entry and readme at once, then in
+    /// database
+    ///
+    /// The readme is rendered from the database changed in a transaction, which
+    /// is only committed once the index is pushed. In pull request mode the
+    /// index entry is changed by a pull request instead.
+    pub async fn yank_package(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        yanked: bool,
+    ) -> Result<()> {
+        if self.publish_mode == IndexPublishMode::PullRequest {
+            return self.propose_yank(name, version, yanked).await;
+        }
+
+        let workspace = self.workspace.lock().await;
+
+        self.check_package_exists(name, version).await?;
+
+        self.database.lock().await.transaction(|database| {
+            database.update_package_yanked(
+                name.normalized_group(),
+                name.normalized_name(),
+                version,
+                yanked,
+            )?;
+            let package_list = render_readme_package_list(database)?;
+            block_in_place(|| {
+                workspace
+                    .index
+                    .yank_package(name, version, yanked, package_list)
+            })
+        })
+    }
+
+    /// Flip the yanked flag in index entry by a pull request, then in database
+    /// and readme once it is merged
+    ///
+    /// The workspace is released while waiting for the pull request, which is
+    /// closed if the yank fails.
+    async fn propose_yank(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        yanked: bool,
+    ) -> Result<()> {
+        let proposal = {
+            let workspace = self.workspace.lock().await;
+            self.check_package_exists(name, version).await?;
+            match block_in_place(|| workspace.index.propose_yank(name, version, yanked))? {
+                Some((branch, diff)) => {
+                    let action = if yanked { "Yank" } else { "Unyank" };
+                    let pull_request = self
+                        .propose_branch(
+                            &branch,
+                            workspace.index.branch(),
+                            &format!("{} `{} {}`", action, name, version),
+                            &format!("```diff\n{}```\n", diff),
+                        )
+                        .await?;
+                    Some((branch, pull_request.number))
+                }
+                None => None,
+            }
+        };
+
+        if let Some((branch, number)) = proposal {
+            let res = self.wait_pull_request(number).await;
+            if res.is_err() {
+                if let Err(error) = self.github.close_pull_request(number).await {
+                    warn!("Failed to close pull request {}: {}", number, error);
+                }
+            }
+            let workspace = self.workspace.lock().await;
+            if let Err(error) = block_in_place(|| workspace.index.delete_branch(&branch)) {
+                warn!("Failed to delete branch `{}`: {}", branch, error);
+            }
+            res?;
+        }
+
+        self.database.lock().await.update_package_yanked(
+            name.normalized_group(),
+            name.normalized_name(),
+            version,
+            yanked,
+        )?;
+        self.regen_readme().await
+    }
+
+    /// Query database and check whether the package version has been published
+    async fn check_package_exists(&self, name: &PackageName, version: &Version) -> Result<()> {
+        let packages = self
+            .database
+            .lock()
+            .await
+            .query_package(Some(name.normalized_group()))?;
+        let exists = packages
+            .iter()
+            .any(|package| package.name == name.normalized_name() && &package.version == version);
+        if !exists {
+            bail!(Error::PackageNotFound {
+                package: name.to_string(),
+                version: version.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct YankState {
+    pub name: PackageName,
+    pub version: Version,
+    pub yanked: bool,
+    pub done: bool,
+    pub error: Option<String>,
+}
+
+impl CommentReport for YankState {
+    fn render_title(&self, _: &Comment) -> Option<&str> {
+        if self.yanked {
+            Some("Yank Package")
+        } else {
+            Some("Unyank Package")
+        }
+    }
+
+    fn render_body(&self, _: &Comment) -> Option<String> {
+        let mut body = String::new();
+
+        if let Some(error) = &self.error {
+            write!(body, "- ❌ *{}*\n\n", error).unwrap();
+        } else if self.done {
+            body += "- ✔️ Done\n";
+        }
+
+        Some(body)
+    }
+
+    fn render_msg(&self, _: &Comment) -> String {
+        if let Some(_) = &self.error {
+            "Yank failed due to the reason above.".to_owned()
+        } else if self.yanked {
+            format!("Package `{}|{}` has been yanked.", self.name, self.version)
+        } else {
+            format!(
+                "Package `{}|{}` has been unyanked.",
+                self.name, self.version
+            )
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use chrono::{DateTime, FixedOffset};
 use rusqlite::{params, Connection};
 use semver::Version;
@@ -15,6 +17,13 @@ impl Database {
         Database { conn }
     }
 
+    /// Open the database file and create the tables if they don't exist
+    pub fn open(path: &Path) -> Result<Database> {
+        let database = Database::new(Connection::open(path)?);
+        database.create_tables()?;
+        Ok(database)
+    }
+
     pub fn create_tables(&self) -> Result<()> {
         self.conn.execute(
             "
@@ -35,6 +44,8 @@ impl Database {
                     homepage VARCHAR,
                     repository VARCHAR,
                     user_id INTERGER NOT NULL,
+                    yanked BOOLEAN NOT NULL DEFAULT 0,
+                    path VARCHAR,
 
                     UNIQUE(group_name, name, version)
                     FOREIGN KEY (user_id)
@@ -50,6 +61,7 @@ impl Database {
                     user_id INTERGER NOT NULL,
                     body VARCHAR NOT NULL,
                     created_at VARCHAR NOT NULL,
+                    issue_number INTERGER,
                     
                     FOREIGN KEY (user_id)
                         REFERENCES users (id)
@@ -57,6 +69,129 @@ impl Database {
             ",
             params![],
         )?;
+        self.conn.execute(
+            "
+                CREATE TABLE IF NOT EXISTS namespace_owners (
+                    group_name VARCHAR NOT NULL,
+                    user_id INTERGER NOT NULL,
+
+                    UNIQUE(group_name, user_id)
+                    FOREIGN KEY (user_id)
+                        REFERENCES users (id)
+                );
+            ",
+            params![],
+        )?;
+        self.conn.execute(
+            "
+                CREATE TABLE IF NOT EXISTS namespace_transfers (
+                    group_name VARCHAR PRIMARY KEY,
+                    from_user_id INTERGER NOT NULL,
+                    to_user_id INTERGER NOT NULL,
+
+                    FOREIGN KEY (from_user_id)
+                        REFERENCES users (id)
+                    FOREIGN KEY (to_user_id)
+                        REFERENCES users (id)
+                );
+            ",
+            params![],
+        )?;
+        self.seed_namespace_owners()?;
+        self.conn.execute(
+            "
+                CREATE TABLE IF NOT EXISTS jobs (
+                    comment_id INTERGER PRIMARY KEY,
+                    command VARCHAR NOT NULL,
+                    step VARCHAR,
+                    effects VARCHAR,
+                    error VARCHAR,
+                    finished BOOLEAN NOT NULL DEFAULT 0,
+
+                    FOREIGN KEY (comment_id)
+                        REFERENCES comments (id)
+                );
+            ",
+            params![],
+        )?;
+        self.conn.execute(
+            "
+                CREATE TABLE IF NOT EXISTS replies (
+                    comment_id INTERGER PRIMARY KEY,
+                    reply_id INTERGER NOT NULL,
+
+                    FOREIGN KEY (comment_id)
+                        REFERENCES comments (id)
+                );
+            ",
+            params![],
+        )?;
+        self.conn.execute(
+            "
+                CREATE TABLE IF NOT EXISTS reactions (
+                    comment_id INTERGER PRIMARY KEY,
+                    reaction_id INTERGER NOT NULL,
+                    content VARCHAR NOT NULL,
+
+                    FOREIGN KEY (comment_id)
+                        REFERENCES comments (id)
+                );
+            ",
+            params![],
+        )?;
+        // Columns introduced after the tables were created by older versions
+        self.add_column("packages", "yanked", "BOOLEAN NOT NULL DEFAULT 0")?;
+        self.add_column("comments", "issue_number", "INTERGER")?;
+        self.add_column("packages", "path", "VARCHAR")?;
+        self.add_column("jobs", "effects", "VARCHAR")?;
+        Ok(())
+    }
+
+    fn add_column(&self, table: &str, column: &str, definition: &str) -> Result<()> {
+        let mut stat = self
+            .conn
+            .prepare(&format!("PRAGMA table_info({});", table))?;
+        let columns: Vec<String> = stat
+            .query_map(params![], |row| row.get("name"))?
+            .collect::<std::result::Result<_, _>>()?;
+        if !columns.iter().any(|name| name == column) {
+            self.conn.execute(
+                &format!(
+                    "ALTER TABLE {} ADD COLUMN {} {};",
+                    table, column, definition
+                ),
+                params![],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Run the closure in a transaction, which is rolled back if it fails
+    pub fn transaction<T>(&self, f: impl FnOnce(&Database) -> Result<T>) -> Result<T> {
+        self.conn.execute_batch("BEGIN TRANSACTION;")?;
+        match f(self) {
+            Ok(val) => {
+                self.conn.execute_batch("COMMIT;")?;
+                Ok(val)
+            }
+            Err(error) => {
+                self.conn.execute_batch("ROLLBACK;")?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Make the publishers of namespaces that have no owner their owners, as
+    /// namespaces used to be owned by their publishers
+    pub fn seed_namespace_owners(&self) -> Result<()> {
+        self.conn.execute(
+            "
+                INSERT OR IGNORE INTO namespace_owners (group_name, user_id)
+                SELECT DISTINCT group_name, user_id FROM packages
+                WHERE group_name NOT IN (SELECT group_name FROM namespace_owners);
+            ",
+            params![],
+        )?;
         Ok(())
     }
 
@@ -106,14 +241,130 @@ impl Database {
     pub fn insert_package(&self, package: Package) -> Result<()> {
         self.conn.execute_named(
             "
-                INSERT INTO packages (group_name, name, version, description, homepage, repository, user_id)
-                VALUES (:group_name, :name, :version, :description, :homepage, :repository, :user_id)
+                INSERT INTO packages (group_name, name, version, description, homepage, repository, user_id, yanked, path)
+                VALUES (:group_name, :name, :version, :description, :homepage, :repository, :user_id, :yanked, :path)
             ",
             &to_params_named(package)?.to_slice(),
         )?;
         Ok(())
     }
 
+    pub fn delete_package(&self, group: &str, name: &str, version: &Version) -> Result<()> {
+        self.conn.execute(
+            "
+                DELETE FROM packages
+                WHERE group_name = ?1 AND name = ?2 AND version = ?3
+            ",
+            params![group, name, version.to_string()],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_package_yanked(
+        &self,
+        group: &str,
+        name: &str,
+        version: &Version,
+        yanked: bool,
+    ) -> Result<()> {
+        self.conn.execute(
+            "
+                UPDATE packages SET yanked = ?4
+                WHERE group_name = ?1 AND name = ?2 AND version = ?3
+            ",
+            params![group, name, version.to_string(), yanked],
+        )?;
+        Ok(())
+    }
+
+    pub fn query_namespace_owners(&self, group: &str) -> Result<Vec<User>> {
+        let mut stat = self.conn.prepare(
+            "
+                SELECT users.* FROM namespace_owners
+                JOIN users ON users.id = namespace_owners.user_id
+                WHERE namespace_owners.group_name = ?1
+                ORDER BY users.name;
+            ",
+        )?;
+        let rows = from_rows::<User>(stat.query(params![group])?);
+        let rows: Result<Vec<_>> = rows
+            .into_iter()
+            .map(|row| row.map_err(Into::into))
+            .collect();
+        Ok(rows?)
+    }
+
+    pub fn insert_namespace_owner(&self, group: &str, user_id: i64) -> Result<()> {
+        self.conn.execute(
+            "
+                INSERT OR IGNORE INTO namespace_owners (group_name, user_id)
+                VALUES (?1, ?2)
+            ",
+            params![group, user_id],
+        )?;
+        Ok(())
+    }
+
+    /// Make the user the only owner of the namespace
+    pub fn set_namespace_owner(&self, group: &str, user_id: i64) -> Result<()> {
+        self.conn.execute(
+            "
+                DELETE FROM namespace_owners WHERE group_name = ?1
+            ",
+            params![group],
+        )?;
+        self.insert_namespace_owner(group, user_id)?;
+        Ok(())
+    }
+
+    pub fn delete_namespace_owner(&self, group: &str, user_id: i64) -> Result<()> {
+        self.conn.execute(
+            "
+                DELETE FROM namespace_owners WHERE group_name = ?1 AND user_id = ?2
+            ",
+            params![group, user_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn query_transfer(&self, group: &str) -> Result<Option<Transfer>> {
+        let mut stat = self.conn.prepare(
+            "
+                SELECT * FROM namespace_transfers WHERE group_name = ?1;
+            ",
+        )?;
+        let mut rows = from_rows::<Transfer>(stat.query(params![group])?);
+        Ok(rows.next().transpose()?)
+    }
+
+    pub fn insert_transfer(&self, transfer: Transfer) -> Result<()> {
+        self.conn.execute_named(
+            "
+                INSERT OR REPLACE INTO namespace_transfers (group_name, from_user_id, to_user_id)
+                VALUES (:group_name, :from_user_id, :to_user_id)
+            ",
+            &to_params_named(transfer)?.to_slice(),
+        )?;
+        Ok(())
+    }
+
+    /// Make the recipient the only owner of the namespace and close the transfer
+    pub fn complete_transfer(&self, transfer: &Transfer) -> Result<()> {
+        self.set_namespace_owner(&transfer.group, transfer.to_user_id)?;
+        self.delete_transfer(&transfer.group)?;
+        Ok(())
+    }
+
+    pub fn delete_transfer(&self, group: &str) -> Result<()> {
+        self.conn.execute(
+            "
+                DELETE FROM namespace_transfers WHERE group_name = ?1
+            ",
+            params![group],
+        )?;
+        Ok(())
+    }
+
     pub fn query_comment(&self, comment_id: i64) -> Result<Option<Comment>> {
         let mut stat = self.conn.prepare(
             "
@@ -124,19 +375,142 @@ impl Database {
         Ok(rows.next().transpose()?)
     }
 
+    /// The most recent comment recorded, which is where to resume listening from
+    pub fn query_last_comment(&self) -> Result<Option<Comment>> {
+        let mut stat = self.conn.prepare(
+            "
+                SELECT * FROM comments ORDER BY id DESC LIMIT 1;
+            ",
+        )?;
+        let mut rows = from_rows::<Comment>(stat.query(params![])?);
+        Ok(rows.next().transpose()?)
+    }
+
     pub fn insert_comment(&self, comment: Comment) -> Result<()> {
         self.conn.execute_named(
             "
-                INSERT INTO comments (id, user_id, body, created_at)
-                VALUES (:id, :user_id, :body, :created_at)
+                INSERT INTO comments (id, user_id, body, created_at, issue_number)
+                VALUES (:id, :user_id, :body, :created_at, :issue_number)
             ",
             &to_params_named(comment)?.to_slice(),
         )?;
         Ok(())
     }
+
+    /// The comment the bot replied to the command comment with
+    pub fn query_reply(&self, comment_id: i64) -> Result<Option<i64>> {
+        let mut stat = self.conn.prepare(
+            "
+                SELECT reply_id FROM replies WHERE comment_id = ?1;
+            ",
+        )?;
+        let mut rows = stat.query(params![comment_id])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert_reply(&self, comment_id: i64, reply_id: i64) -> Result<()> {
+        self.conn.execute(
+            "
+                INSERT OR REPLACE INTO replies (comment_id, reply_id)
+                VALUES (?1, ?2)
+            ",
+            params![comment_id, reply_id],
+        )?;
+        Ok(())
+    }
+
+    /// The reaction of the bot on the comment
+    pub fn query_reaction(&self, comment_id: i64) -> Result<Option<Reaction>> {
+        let mut stat = self.conn.prepare(
+            "
+                SELECT * FROM reactions WHERE comment_id = ?1;
+            ",
+        )?;
+        let mut rows = from_rows::<Reaction>(stat.query(params![comment_id])?);
+        Ok(rows.next().transpose()?)
+    }
+
+    pub fn insert_reaction(&self, reaction: Reaction) -> Result<()> {
+        self.conn.execute_named(
+            "
+                INSERT OR REPLACE INTO reactions (comment_id, reaction_id, content)
+                VALUES (:comment_id, :reaction_id, :content)
+            ",
+            &to_params_named(reaction)?.to_slice(),
+        )?;
+        Ok(())
+    }
+
+    pub fn query_job(&self, comment_id: i64) -> Result<Option<Job>> {
+        let mut stat = self.conn.prepare(
+            "
+                SELECT * FROM jobs WHERE comment_id = ?1;
+            ",
+        )?;
+        let mut rows = from_rows::<Job>(stat.query(params![comment_id])?);
+        Ok(rows.next().transpose()?)
+    }
+
+    pub fn query_unfinished_jobs(&self) -> Result<Vec<Job>> {
+        let mut stat = self.conn.prepare(
+            "
+                SELECT * FROM jobs WHERE finished = 0 ORDER BY comment_id;
+            ",
+        )?;
+        let rows = from_rows::<Job>(stat.query(params![])?);
+        let rows: Result<Vec<_>> = rows
+            .into_iter()
+            .map(|row| row.map_err(Into::into))
+            .collect();
+        Ok(rows?)
+    }
+
+    pub fn insert_job(&self, job: Job) -> Result<()> {
+        self.conn.execute_named(
+            "
+                INSERT OR REPLACE INTO jobs (comment_id, command, step, effects, error, finished)
+                VALUES (:comment_id, :command, :step, :effects, :error, :finished)
+            ",
+            &to_params_named(job)?.to_slice(),
+        )?;
+        Ok(())
+    }
+
+    pub fn update_job_step(&self, comment_id: i64, step: &str) -> Result<()> {
+        self.conn.execute(
+            "
+                UPDATE jobs SET step = ?2 WHERE comment_id = ?1
+            ",
+            params![comment_id, step],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_job_effects(&self, comment_id: i64, effects: &str) -> Result<()> {
+        self.conn.execute(
+            "
+                UPDATE jobs SET effects = ?2 WHERE comment_id = ?1
+            ",
+            params![comment_id, effects],
+        )?;
+        Ok(())
+    }
+
+    pub fn finish_job(&self, comment_id: i64, error: Option<&str>) -> Result<()> {
+        self.conn.execute(
+            "
+                UPDATE jobs SET error = ?2, finished = 1 WHERE comment_id = ?1
+            ",
+            params![comment_id, error],
+        )?;
+        Ok(())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: i64,
     pub name: String,
@@ -148,6 +522,9 @@ pub struct Comment {
     pub user_id: i64,
     pub body: String,
     pub created_at: DateTime<FixedOffset>,
+    /// Unknown for comments recorded when only the index issue was listened
+    #[serde(default)]
+    pub issue_number: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -160,4 +537,44 @@ pub struct Package {
     pub homepage: Option<String>,
     pub repository: Option<String>,
     pub user_id: i64,
+    #[serde(default)]
+    pub yanked: bool,
+    /// Directory of the package in its repository, if not at the root
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+/// A pending namespace transfer waiting for the recipient to accept
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Transfer {
+    #[serde(rename = "group_name")]
+    pub group: String,
+    pub from_user_id: i64,
+    pub to_user_id: i64,
+}
+
+/// A command being executed, which is recorded so that it can be resumed or
+/// reported as failed if the bot restarts in the middle of it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub comment_id: i64,
+    /// The command serialized in json
+    pub command: String,
+    /// The last `PublishStep` reached, serialized in json
+    pub step: Option<String>,
+    /// The side effects made by a publish so far, serialized in json
+    #[serde(default)]
+    pub effects: Option<String>,
+    pub error: Option<String>,
+    pub finished: bool,
+}
+
+/// The reaction the bot has left on a comment, which is replaced as the state
+/// of the command changes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reaction {
+    pub comment_id: i64,
+    pub reaction_id: i64,
+    /// The content of the reaction in Github API, e.g. `eyes`
+    pub content: String,
 }
@@ -7,11 +7,35 @@ pub enum Error {
     #[fail(display = "Gibhub API error: {}", _0)]
     Github(String),
 
+    #[fail(display = "Missing config `{}`", _0)]
+    MissingConfig(&'static str),
+
     #[fail(display = "No initial commit in remote index")]
     NoInitialCommit,
 
-    #[fail(display = "Namespace `{}` has been taken by @{}", group, owner)]
-    NamespaceIsTaken { group: String, owner: String },
+    #[fail(display = "Namespace `{}` is owned by {}", group, owners)]
+    NamespaceIsTaken { group: String, owners: String },
+
+    #[fail(display = "You are not an owner of namespace `{}`", group)]
+    NotNamespaceOwner { group: String },
+
+    #[fail(display = "@{} is the last owner of namespace `{}`", user, group)]
+    LastNamespaceOwner { group: String, user: String },
+
+    #[fail(display = "@{} is not an owner of namespace `{}`", user, group)]
+    NotAnOwner { group: String, user: String },
+
+    #[fail(display = "`{}` is not a valid namespace", _0)]
+    InvalidNamespace(String),
+
+    #[fail(
+        display = "There is no pending transfer of namespace `{}` to you",
+        group
+    )]
+    NoPendingTransfer { group: String },
+
+    #[fail(display = "Github user @{} does not exist", _0)]
+    UserNotFound(String),
 
     #[fail(display = "Package `{} {}` has been published", package, version)]
     PackageExists {
@@ -19,15 +43,30 @@ pub enum Error {
         version: semver::Version,
     },
 
+    #[fail(display = "Package `{} {}` does not exist in index", package, version)]
+    PackageNotFound {
+        package: String,
+        version: semver::Version,
+    },
+
     #[fail(
         display = "Package tarball is too big ({} bytes) while the maximum size is {}",
         size, limit
     )]
     PackageOversize { size: u64, limit: u64 },
 
+    #[fail(display = "Remote requires credentials, which are not configured")]
+    NoGitCredentials,
+
     #[fail(display = "Git push failed: {}", _0)]
     GitPush(String),
 
+    #[fail(display = "Git push rejected by concurrent changes: {}", _0)]
+    GitPushRejected(String),
+
+    #[fail(display = "Concurrent changes conflict in {}", _0)]
+    GitConflict(String),
+
     #[fail(
         display = "Package contains non-index dependency `{}`({})",
         dependency, resolution
@@ -43,9 +82,57 @@ pub enum Error {
     )]
     DependencyNotFound { dependency: String },
 
+    #[fail(display = "Pull request #{} was closed without merging", _0)]
+    PullRequestClosed(i64),
+
+    #[fail(display = "Checks failed on pull request #{}", _0)]
+    PullRequestChecksFailed(i64),
+
+    #[fail(display = "Job was interrupted by a restart of elba-bot")]
+    JobInterrupted,
+
+    #[fail(
+        display = "{} discrepancies remain between index, store and database",
+        _0
+    )]
+    Inconsistent(usize),
+
+    #[fail(
+        display = "Tarball of package `{} {}` contains no manifest",
+        package, version
+    )]
+    ManifestNotFound {
+        package: String,
+        version: semver::Version,
+    },
+
+    #[fail(display = "Package path `{}` is not a directory in the repository", _0)]
+    InvalidPackagePath(String),
+
+    #[fail(display = "Database is not empty")]
+    DatabaseNotEmpty,
+
+    #[fail(
+        display = "Publishers of {} packages are unknown, attribute them with `--assume-publisher`",
+        _0
+    )]
+    UnknownPublishers(usize),
+
+    #[fail(display = "Commit signing failed: {}", _0)]
+    CommitSigning(String),
+
+    #[fail(
+        display = "{} commits touching metafiles are not signed by the bot",
+        _0
+    )]
+    UnsignedCommits(usize),
+
     #[fail(display = "Repository is bare")]
     RepoIsBare,
 
+    #[fail(display = "Failed to detect the default branch of `{}`", _0)]
+    NoDefaultBranch(String),
+
     #[fail(
         display = "Tarball checksum mismatched between local {} and store {}",
         local_cksum, download_cksum
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use elba::remote::{resolution::DirectRes, RawEntry};
+use failure::bail;
+use log::info;
+use semver::Version;
+
+use crate::config::{IndexPublishMode, CONFIG};
+use crate::controller::render_readme_package_list;
+use crate::database::{self, Database};
+use crate::error::{Error, Result};
+use crate::workspace::Workspace;
+
+/// Check that the index, the store and the database agree with each other,
+/// print the discrepancies found and optionally repair them
+///
+/// The index is regarded as the source of truth of what has been published,
+/// so the database and the README are repaired to follow it, apart from the
+/// README in pull request mode. Problems of the store can not be repaired and
+/// are only reported.
+pub fn run(repair: bool) -> Result<()> {
+    let workspace = Workspace::new()?;
+    let database = Database::open(&CONFIG.db_path)?;
+
+    let discrepancies = check(&workspace, &database)?;
+    for discrepancy in &discrepancies {
+        println!("{}", discrepancy);
+    }
+    println!("{} discrepancies found", discrepancies.len());
+
+    let remaining = if repair && !discrepancies.is_empty() {
+        let unrepaired = repair_all(&workspace, &database, &discrepancies)?;
+        println!(
+            "{} discrepancies repaired",
+            discrepancies.len() - unrepaired.len()
+        );
+        unrepaired.len()
+    } else {
+        discrepancies.len()
+    };
+
+    if remaining > 0 {
+        bail!(Error::Inconsistent(remaining));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum Discrepancy {
+    /// An index entry has no package row in database
+    MissingInDatabase { package: PackageKey, yanked: bool },
+    /// A package row in database has no index entry
+    MissingInIndex { package: PackageKey },
+    /// The index and the database disagree on whether the package is yanked
+    YankedMismatch {
+        package: PackageKey,
+        index_yanked: bool,
+    },
+    /// The index entry does not point to a tarball with checksum
+    InvalidLocation { package: PackageKey },
+    /// The tarball of the index entry does not exist in store
+    MissingInStore { package: PackageKey },
+    /// The tarball in store does not match the checksum in index entry
+    ChecksumMismatch {
+        package: PackageKey,
+        index_cksum: String,
+        store_cksum: String,
+    },
+    /// The README does not list the packages in database
+    ReadmeOutdated,
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::MissingInDatabase { package, .. } => {
+                write!(f, "[missing-in-database] {}", package)
+            }
+            Discrepancy::MissingInIndex { package } => write!(f, "[missing-in-index] {}", package),
+            Discrepancy::YankedMismatch {
+                package,
+                index_yanked,
+            } => write!(
+                f,
+                "[yanked-mismatch] {}: yanked is {} in index but {} in database",
+                package, index_yanked, !index_yanked
+            ),
+            Discrepancy::InvalidLocation { package } => {
+                write!(f, "[invalid-location] {}", package)
+            }
+            Discrepancy::MissingInStore { package } => write!(f, "[missing-in-store] {}", package),
+            Discrepancy::ChecksumMismatch {
+                package,
+                index_cksum,
+                store_cksum,
+            } => write!(
+                f,
+                "[checksum-mismatch] {}: {} in index but {} in store",
+                package, index_cksum, store_cksum
+            ),
+            Discrepancy::ReadmeOutdated => write!(f, "[readme-outdated]"),
+        }
+    }
+}
+
+/// Normalized group, name and version of a package
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PackageKey {
+    pub group: String,
+    pub name: String,
+    pub version: Version,
+}
+
+impl PackageKey {
+    pub fn from_entry(entry: &RawEntry) -> Self {
+        PackageKey {
+            group: entry.name.normalized_group().to_string(),
+            name: entry.name.normalized_name().to_string(),
+            version: entry.version.clone(),
+        }
+    }
+
+    fn from_package(package: &database::Package) -> Self {
+        PackageKey {
+            group: package.group.clone(),
+            name: package.name.clone(),
+            version: package.version.clone(),
+        }
+    }
+}
+
+impl fmt::Display for PackageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}/{} {}`", self.group, self.name, self.version)
+    }
+}
+
+pub fn check(workspace: &Workspace, database: &Database) -> Result<Vec<Discrepancy>> {
+    let mut discrepancies = Vec::new();
+
+    info!("Checking index entries against database");
+    let entries = workspace.index.load_all_entries()?;
+    let packages: HashMap<PackageKey, database::Package> = database
+        .query_package(None)?
+        .into_iter()
+        .map(|package| (PackageKey::from_package(&package), package))
+        .collect();
+    for entry in &entries {
+        let key = PackageKey::from_entry(entry);
+        match packages.get(&key) {
+            None => discrepancies.push(Discrepancy::MissingInDatabase {
+                package: key,
+                yanked: entry.yanked,
+            }),
+            Some(package) if package.yanked != entry.yanked => {
+                discrepancies.push(Discrepancy::YankedMismatch {
+                    package: key,
+                    index_yanked: entry.yanked,
+                })
+            }
+            Some(_) => (),
+        }
+    }
+    let entry_keys: Vec<PackageKey> = entries.iter().map(PackageKey::from_entry).collect();
+    let mut missing_in_index: Vec<&PackageKey> = packages
+        .keys()
+        .filter(|key| !entry_keys.contains(key))
+        .collect();
+    missing_in_index
+        .sort_by(|a, b| (&a.group, &a.name, &a.version).cmp(&(&b.group, &b.name, &b.version)));
+    for key in missing_in_index {
+        discrepancies.push(Discrepancy::MissingInIndex {
+            package: key.clone(),
+        });
+    }
+
+    info!("Checking index entries against store");
+    workspace.store.refresh()?;
+    for entry in &entries {
+        let key = PackageKey::from_entry(entry);
+        let index_cksum = match &entry.location {
+            Some(DirectRes::Tar {
+                cksum: Some(cksum), ..
+            }) => cksum.hash.clone(),
+            _ => {
+                discrepancies.push(Discrepancy::InvalidLocation { package: key });
+                continue;
+            }
+        };
+        match workspace
+            .store
+            .package_checksum(&entry.name, &entry.version)?
+        {
+            None => discrepancies.push(Discrepancy::MissingInStore { package: key }),
+            Some(store_cksum) if store_cksum != index_cksum => {
+                discrepancies.push(Discrepancy::ChecksumMismatch {
+                    package: key,
+                    index_cksum,
+                    store_cksum,
+                })
+            }
+            Some(_) => (),
+        }
+    }
+
+    info!("Checking index readme against database");
+    let package_list = render_readme_package_list(database)?;
+    if !workspace.index.is_readme_up_to_date(&package_list)? {
+        discrepancies.push(Discrepancy::ReadmeOutdated);
+    }
+
+    Ok(discrepancies)
+}
+
+/// Repair the discrepancies and return the ones that can't be repaired
+fn repair_all<'a>(
+    workspace: &Workspace,
+    database: &Database,
+    discrepancies: &'a [Discrepancy],
+) -> Result<Vec<&'a Discrepancy>> {
+    let mut unrepaired = Vec::new();
+    let mut readme_outdated = false;
+
+    for discrepancy in discrepancies {
+        info!("Repairing {}", discrepancy);
+        match discrepancy {
+            // The publisher is unknown, so attribute the package to an owner of the namespace
+            Discrepancy::MissingInDatabase { package, yanked } => {
+                match database.query_namespace_owners(&package.group)?.first() {
+                    Some(owner) => {
+                        println!("Assuming {} is published by @{}", package, owner.name);
+                        database.insert_package(database::Package {
+                            group: package.group.clone(),
+                            name: package.name.clone(),
+                            version: package.version.clone(),
+                            description: None,
+                            homepage: None,
+                            repository: None,
+                            user_id: owner.id,
+                            yanked: *yanked,
+                            path: None,
+                        })?;
+                        readme_outdated = true;
+                    }
+                    None => unrepaired.push(discrepancy),
+                }
+            }
+            Discrepancy::MissingInIndex { package } => {
+                database.delete_package(&package.group, &package.name, &package.version)?;
+                readme_outdated = true;
+            }
+            Discrepancy::YankedMismatch {
+                package,
+                index_yanked,
+            } => {
+                database.update_package_yanked(
+                    &package.group,
+                    &package.name,
+                    &package.version,
+                    *index_yanked,
+                )?;
+                readme_outdated = true;
+            }
+            Discrepancy::ReadmeOutdated => readme_outdated = true,
+            Discrepancy::InvalidLocation { .. }
+            | Discrepancy::MissingInStore { .. }
+            | Discrepancy::ChecksumMismatch { .. } => unrepaired.push(discrepancy),
+        }
+    }
+
+    if readme_outdated {
+        match CONFIG.index_publish_mode {
+            IndexPublishMode::Push => {
+                let package_list = render_readme_package_list(database)?;
+                workspace.index.update_readme(package_list)?;
+            }
+            // The index branch only takes pull requests, which need Github
+            IndexPublishMode::PullRequest => {
+                println!("Run `regen-readme` to propose the README by a pull request");
+                unrepaired.extend(
+                    discrepancies
+                        .iter()
+                        .filter(|discrepancy| matches!(discrepancy, Discrepancy::ReadmeOutdated)),
+                );
+            }
+        }
+    }
+
+    Ok(unrepaired)
+}
+
+#[cfg(test)]
+mod test {
+    use elba::package::Name as PackageName;
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::testing::{self, ROOT};
+
+    /// Publish the package into the index and the store, leaving the database
+    /// and the README alone
+    fn publish(
+        workspace: &Workspace,
+        name: &str,
+        publisher: &database::User,
+    ) -> (PackageName, Version) {
+        let dir = ROOT.join(name.replace('/', "-"));
+        testing::init_package_repo(&dir, name, "0.1.0");
+        let (tarball, manifest) = elba::cli::index::package(&dir).unwrap();
+        let location = workspace
+            .store
+            .upload_package(&manifest, &tarball, publisher)
+            .unwrap();
+        workspace
+            .index
+            .update_package(&manifest, &location, publisher, None, None, String::new())
+            .unwrap();
+        (manifest.package.name, manifest.package.version)
+    }
+
+    fn package(name: &str, user_id: i64) -> database::Package {
+        database::Package {
+            group: "fsck".to_owned(),
+            name: name.to_owned(),
+            version: "0.1.0".parse().unwrap(),
+            description: None,
+            homepage: None,
+            repository: None,
+            user_id,
+            yanked: false,
+            path: None,
+        }
+    }
+
+    /// The discrepancies seeded by the test in order, since the index is shared
+    /// with the packages of other tests
+    fn seeded<'a>(discrepancies: impl IntoIterator<Item = &'a Discrepancy>) -> Vec<String> {
+        let mut seeded: Vec<String> = discrepancies
+            .into_iter()
+            .map(ToString::to_string)
+            .filter(|discrepancy| {
+                discrepancy.contains("`fsck/") || discrepancy == "[readme-outdated]"
+            })
+            .collect();
+        seeded.sort();
+        seeded
+    }
+
+    #[test]
+    fn test_check_and_repair() {
+        let _lock = testing::lock();
+        let workspace = Workspace::new().unwrap();
+        let database = Database::new(Connection::open_in_memory().unwrap());
+        database.create_tables().unwrap();
+        let alice = database::User {
+            id: 1,
+            name: "alice".to_owned(),
+        };
+        database.insert_user(alice.clone()).unwrap();
+        database.insert_namespace_owner("fsck", alice.id).unwrap();
+
+        // Published without a package row
+        publish(&workspace, "fsck/a", &alice);
+        // Yanked in index only
+        let (name, version) = publish(&workspace, "fsck/b", &alice);
+        workspace
+            .index
+            .yank_package(&name, &version, true, String::new())
+            .unwrap();
+        database.insert_package(package("b", alice.id)).unwrap();
+        // A package row without index entry
+        database.insert_package(package("c", alice.id)).unwrap();
+        // Missing the tarball
+        let (name, version) = publish(&workspace, "fsck/d", &alice);
+        workspace.store.delete_package(&name, &version).unwrap();
+        database.insert_package(package("d", alice.id)).unwrap();
+
+        let discrepancies = check(&workspace, &database).unwrap();
+        assert_eq!(
+            seeded(&discrepancies),
+            [
+                "[missing-in-database] `fsck/a 0.1.0`",
+                "[missing-in-index] `fsck/c 0.1.0`",
+                "[missing-in-store] `fsck/d 0.1.0`",
+                "[readme-outdated]",
+                "[yanked-mismatch] `fsck/b 0.1.0`: yanked is true in index but false in database",
+            ]
+        );
+
+        // The database and the README follow the index, but the store can't be
+        // repaired
+        let unrepaired = repair_all(&workspace, &database, &discrepancies).unwrap();
+        assert_eq!(
+            seeded(unrepaired.iter().copied()),
+            ["[missing-in-store] `fsck/d 0.1.0`"]
+        );
+        let mut packages = database.query_package(Some("fsck")).unwrap();
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+        let packages: Vec<_> = packages
+            .iter()
+            .map(|package| (&*package.name, package.user_id, package.yanked))
+            .collect();
+        assert_eq!(
+            packages,
+            [
+                ("a", alice.id, false),
+                ("b", alice.id, true),
+                ("d", alice.id, false)
+            ]
+        );
+
+        let discrepancies = check(&workspace, &database).unwrap();
+        assert_eq!(
+            seeded(&discrepancies),
+            ["[missing-in-store] `fsck/d 0.1.0`"]
+        );
+    }
+}
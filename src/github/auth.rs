@@ -0,0 +1,309 @@
+use std::fs;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use lazy_static::lazy_static;
+use log::info;
+use reqwest::{blocking, Client};
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+use crate::error::{Error, Result};
+
+lazy_static! {
+    static ref INSTALLATION_TOKEN: TokenCache = TokenCache::default();
+}
+
+const API_URL: &str = "https://api.github.com";
+
+/// Installation tokens are refreshed this long before they expire, so that
+/// they don't expire in the middle of a push
+const TOKEN_REFRESH_MARGIN: i64 = 5;
+
+/// Whether the bot authenticates as a Github App rather than a user
+pub fn is_app() -> bool {
+    CONFIG.github_app_id.is_some()
+}
+
+/// The token to authenticate REST calls with
+///
+/// As a Github App, it's an installation token that is exchanged and
+/// refreshed on demand.
+pub async fn token() -> Result<String> {
+    if !is_app() {
+        return Ok(CONFIG
+            .access_token
+            .clone()
+            .ok_or(Error::MissingConfig("ACCESS_TOKEN"))?);
+    }
+    if let Some(token) = INSTALLATION_TOKEN.get() {
+        return Ok(token);
+    }
+    let token = exchange_installation_token(API_URL, installation_id()?, &app_jwt()?).await?;
+    Ok(INSTALLATION_TOKEN.set(token))
+}
+
+/// The user name and password to push over HTTPS with
+pub fn git_credentials() -> Result<(String, String)> {
+    if is_app() {
+        Ok(("x-access-token".to_owned(), blocking_installation_token()?))
+    } else {
+        let email = CONFIG
+            .bot_email
+            .clone()
+            .ok_or(Error::MissingConfig("BOT_EMAIL"))?;
+        let pwd = CONFIG
+            .bot_pwd
+            .clone()
+            .ok_or(Error::MissingConfig("BOT_PWD"))?;
+        Ok((email, pwd))
+    }
+}
+
+/// The slug of the Github App, whose bot user is named `{slug}[bot]`
+pub async fn app_slug() -> Result<String> {
+    #[derive(Deserialize)]
+    struct App {
+        slug: String,
+    }
+
+    let app: App = Client::new()
+        .get(&format!("{}/app", API_URL))
+        .bearer_auth(app_jwt()?)
+        .header("accept", "application/vnd.github.machine-man-preview+json")
+        .header("user-agent", &CONFIG.bot_name)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(app.slug)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct InstallationToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// The installation token in use, which is exchanged again once it's about to
+/// expire
+///
+/// The lock is not held while exchanging, so concurrent refreshes may exchange
+/// a token each, which is harmless.
+#[derive(Default)]
+struct TokenCache(Mutex<Option<InstallationToken>>);
+
+impl TokenCache {
+    /// The token in use, unless it's about to expire
+    fn get(&self) -> Option<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|token| token.expires_at - Duration::minutes(TOKEN_REFRESH_MARGIN) > Utc::now())
+            .map(|token| token.token.clone())
+    }
+
+    /// Keep the exchanged token, returning it
+    fn set(&self, token: InstallationToken) -> String {
+        info!(
+            "Refreshed installation token, expires at {}",
+            token.expires_at
+        );
+        let token_str = token.token.clone();
+        *self.0.lock().unwrap() = Some(token);
+        token_str
+    }
+}
+
+/// The installation token for git, whose credential callbacks can't wait
+/// asynchronously
+fn blocking_installation_token() -> Result<String> {
+    if let Some(token) = INSTALLATION_TOKEN.get() {
+        return Ok(token);
+    }
+    let token = blocking_exchange_installation_token(API_URL, installation_id()?, &app_jwt()?)?;
+    Ok(INSTALLATION_TOKEN.set(token))
+}
+
+fn installation_id() -> Result<u64> {
+    Ok(CONFIG
+        .github_app_installation_id
+        .ok_or(Error::MissingConfig("GITHUB_APP_INSTALLATION_ID"))?)
+}
+
+fn access_tokens_url(api_url: &str, installation_id: u64) -> String {
+    format!(
+        "{}/app/installations/{}/access_tokens",
+        api_url, installation_id
+    )
+}
+
+/// Exchange the JWT of the Github App for a token of the installation
+async fn exchange_installation_token(
+    api_url: &str,
+    installation_id: u64,
+    jwt: &str,
+) -> Result<InstallationToken> {
+    Ok(Client::new()
+        .post(&access_tokens_url(api_url, installation_id))
+        .bearer_auth(jwt)
+        .header("accept", "application/vnd.github.machine-man-preview+json")
+        .header("user-agent", &CONFIG.bot_name)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+fn blocking_exchange_installation_token(
+    api_url: &str,
+    installation_id: u64,
+    jwt: &str,
+) -> Result<InstallationToken> {
+    Ok(blocking::Client::new()
+        .post(&access_tokens_url(api_url, installation_id))
+        .bearer_auth(jwt)
+        .header("accept", "application/vnd.github.machine-man-preview+json")
+        .header("user-agent", &CONFIG.bot_name)
+        .send()?
+        .error_for_status()?
+        .json()?)
+}
+
+#[derive(Debug, Serialize)]
+struct Claims {
+    iat: i64,
+    exp: i64,
+    iss: u64,
+}
+
+/// Sign a JWT with the private key of the Github App
+fn app_jwt() -> Result<String> {
+    let app_id = CONFIG
+        .github_app_id
+        .ok_or(Error::MissingConfig("GITHUB_APP_ID"))?;
+    let key_path = CONFIG
+        .github_app_private_key
+        .as_ref()
+        .ok_or(Error::MissingConfig("GITHUB_APP_PRIVATE_KEY"))?;
+    let key = EncodingKey::from_rsa_pem(&fs::read(key_path)?)?;
+
+    // Backdate the token against clock drift, and Github allows 10 minutes at most
+    let now = Utc::now();
+    let claims = Claims {
+        iat: (now - Duration::seconds(60)).timestamp(),
+        exp: (now + Duration::minutes(9)).timestamp(),
+        iss: app_id,
+    };
+    Ok(jsonwebtoken::encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &key,
+    )?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+    use crate::testing;
+
+    /// Serve an installation token per request, expiring in the given minutes,
+    /// returning the url of the fake API and the requests it receives
+    fn fake_api(expires_in: Vec<i64>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (i, minutes) in expires_in.into_iter().enumerate() {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..len]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+
+                let body = format!(
+                    r#"{{"token":"token-{}","expires_at":"{}"}}"#,
+                    i,
+                    (Utc::now() + Duration::minutes(minutes)).to_rfc3339()
+                );
+                write!(
+                    stream,
+                    "HTTP/1.1 201 Created\r\ncontent-type: application/json\r\n\
+                     content-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    /// Exchange a token through the cache like `token` does
+    async fn cached_token(cache: &TokenCache, url: &str) -> String {
+        if let Some(token) = cache.get() {
+            return token;
+        }
+        let token = exchange_installation_token(url, 42, "jwt").await.unwrap();
+        cache.set(token)
+    }
+
+    #[tokio::test]
+    async fn test_installation_token_refresh() {
+        let _lock = testing::lock();
+        // The first token expires within the refresh margin
+        let (url, server) = fake_api(vec![1, 60, 60]);
+        let cache = TokenCache::default();
+
+        assert_eq!(cached_token(&cache, &url).await, "token-0");
+        assert_eq!(cached_token(&cache, &url).await, "token-1");
+        // The second one is used until it's about to expire
+        assert_eq!(cache.get().unwrap(), "token-1");
+        assert_eq!(cached_token(&cache, &url).await, "token-1");
+
+        // Git exchanges tokens outside of async context
+        let token = thread::spawn(move || blocking_exchange_installation_token(&url, 42, "jwt"))
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.token, "token-2");
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        for request in requests {
+            assert!(
+                request.starts_with("POST /app/installations/42/access_tokens "),
+                "{}",
+                request
+            );
+            assert!(
+                request.to_lowercase().contains("authorization: bearer jwt"),
+                "{}",
+                request
+            );
+        }
+    }
+
+    /// A personal access token is resolved without blocking, which would panic
+    /// on the basic scheduler of this test
+    #[tokio::test]
+    async fn test_static_token() {
+        let _lock = testing::lock();
+        assert_eq!(token().await.unwrap(), "");
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, Utc};
+use git2::{Repository, Signature};
+
+use super::*;
+use crate::error::{Error, Result};
+
+/// The number of the index issue, which the fake hosts only
+pub const ISSUE_NUMBER: i64 = 1;
+
+/// Ids are unique across all fakes, since the tests share the database which
+/// records comments by id
+static NEXT_ID: AtomicI64 = AtomicI64::new(2);
+
+fn next_id() -> i64 {
+    NEXT_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+/// An in-memory Github hosting the index issue, for driving the controller
+/// without network access
+///
+/// Pull requests are merged into the index repository for real, so that tests
+/// see the index as it is after a merge.
+pub struct FakeGithub {
+    viewer: User,
+    state: Mutex<FakeState>,
+}
+
+struct FakeState {
+    users: Vec<User>,
+    comments: Vec<FakeComment>,
+    /// Labels of every issue
+    labels: HashMap<i64, Vec<String>>,
+    /// Id, comment id and content of reactions
+    reactions: Vec<(i64, i64, Reaction)>,
+    pull_requests: Vec<PullRequest>,
+    /// Numbers of the pull requests approved by a maintainer
+    approved: Vec<i64>,
+    /// The state of checks on every commit
+    checks: CheckState,
+    /// Whether anything has changed since the last listing, which mimics the
+    /// ETAG of the real one
+    modified: bool,
+    list_count: usize,
+}
+
+struct FakeComment {
+    comment: Comment,
+    updated_at: DateTime<FixedOffset>,
+}
+
+impl FakeGithub {
+    pub fn new(bot_name: &str) -> Self {
+        let viewer = User {
+            id: 1,
+            name: bot_name.to_owned(),
+        };
+        FakeGithub {
+            viewer: viewer.clone(),
+            state: Mutex::new(FakeState {
+                users: vec![viewer],
+                comments: Vec::new(),
+                labels: HashMap::new(),
+                reactions: Vec::new(),
+                pull_requests: Vec::new(),
+                approved: Vec::new(),
+                checks: CheckState::Success,
+                modified: true,
+                list_count: 0,
+            }),
+        }
+    }
+
+    pub fn add_user(&self, user_name: &str) -> User {
+        let user = User {
+            id: next_id(),
+            name: user_name.to_owned(),
+        };
+        self.state.lock().unwrap().users.push(user.clone());
+        user
+    }
+
+    /// Post a comment on the index issue
+    pub fn post_comment(&self, user: &User, body: &str) -> i64 {
+        self.push_comment(ISSUE_NUMBER, user, body).id
+    }
+
+    /// Post a comment on the issue, returning the comment
+    pub fn push_comment(&self, issue_number: i64, user: &User, body: &str) -> Comment {
+        let mut state = self.state.lock().unwrap();
+        let now = now();
+        let comment = Comment {
+            id: next_id(),
+            user: user.clone(),
+            body: body.to_owned(),
+            created_at: now,
+            issue_number,
+        };
+        state.comments.push(FakeComment {
+            comment: comment.clone(),
+            updated_at: now,
+        });
+        state.modified = true;
+        comment
+    }
+
+    pub fn add_label(&self, issue_number: i64, label: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .labels
+            .entry(issue_number)
+            .or_default()
+            .push(label.to_owned());
+    }
+
+    /// All comments on the issues, in the order they are posted
+    pub fn comments(&self) -> Vec<Comment> {
+        self.state
+            .lock()
+            .unwrap()
+            .comments
+            .iter()
+            .map(|fake| fake.comment.clone())
+            .collect()
+    }
+
+    pub fn comment_body(&self, comment_id: i64) -> Option<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .comments
+            .iter()
+            .find(|fake| fake.comment.id == comment_id)
+            .map(|fake| fake.comment.body.clone())
+    }
+
+    pub fn reactions(&self, comment_id: i64) -> Vec<Reaction> {
+        self.state
+            .lock()
+            .unwrap()
+            .reactions
+            .iter()
+            .filter(|(_, id, _)| *id == comment_id)
+            .map(|(_, _, reaction)| *reaction)
+            .collect()
+    }
+
+    fn set_pull_request(
+        &self,
+        number: i64,
+        f: impl FnOnce(&mut PullRequest) -> Result<()>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let pull_request = state
+            .pull_requests
+            .iter_mut()
+            .find(|pull_request| pull_request.number == number)
+            .ok_or_else(|| Error::Github(format!("pull request {} not found", number)))?;
+        f(pull_request)
+    }
+
+    /// Approve the pull request as a maintainer
+    pub fn approve_pull_request(&self, number: i64) {
+        self.state.lock().unwrap().approved.push(number);
+    }
+
+    pub fn set_checks(&self, checks: CheckState) {
+        self.state.lock().unwrap().checks = checks;
+    }
+
+    /// How many times the issue comments have been listed
+    pub fn list_count(&self) -> usize {
+        self.state.lock().unwrap().list_count
+    }
+}
+
+#[async_trait]
+impl GithubApi for FakeGithub {
+    fn viewer_id(&self) -> i64 {
+        self.viewer.id
+    }
+
+    async fn issue_comments(
+        &self,
+        since: Option<DateTime<FixedOffset>>,
+    ) -> Result<Option<GithubResponse<Vec<Comment>>>> {
+        let mut state = self.state.lock().unwrap();
+        state.list_count += 1;
+        if !state.modified {
+            return Ok(None);
+        }
+        state.modified = false;
+
+        let val = state
+            .comments
+            .iter()
+            .filter(|fake| since.map_or(true, |since| fake.updated_at >= since))
+            .map(|fake| fake.comment.clone())
+            .collect();
+        Ok(Some(GithubResponse { val, date: now() }))
+    }
+
+    async fn query_user(&self, user_name: &str) -> Result<User> {
+        self.state
+            .lock()
+            .unwrap()
+            .users
+            .iter()
+            .find(|user| user.name.eq_ignore_ascii_case(user_name))
+            .cloned()
+            .ok_or_else(|| Error::UserNotFound(user_name.to_owned()).into())
+    }
+
+    async fn update_comment(&self, comment_id: i64, body: String) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let fake = state
+            .comments
+            .iter_mut()
+            .find(|fake| fake.comment.id == comment_id)
+            .ok_or_else(|| Error::Github(format!("comment {} not found", comment_id)))?;
+        fake.comment.body = body;
+        fake.updated_at = now();
+        state.modified = true;
+        Ok(())
+    }
+
+    async fn create_comment(&self, issue_number: i64, body: String) -> Result<Comment> {
+        Ok(self.push_comment(issue_number, &self.viewer, &body))
+    }
+
+    async fn issue_labels(&self, issue_number: i64) -> Result<Vec<String>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .labels
+            .get(&issue_number)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn create_reaction(&self, comment_id: i64, reaction: Reaction) -> Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        // Reacting the same twice returns the existing reaction
+        if let Some((id, _, _)) = state
+            .reactions
+            .iter()
+            .find(|(_, id, content)| *id == comment_id && *content == reaction)
+        {
+            return Ok(*id);
+        }
+        let id = next_id();
+        state.reactions.push((id, comment_id, reaction));
+        Ok(id)
+    }
+
+    async fn delete_reaction(&self, comment_id: i64, reaction_id: i64) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .reactions
+            .retain(|(id, comment, _)| *id != reaction_id || *comment != comment_id);
+        Ok(())
+    }
+
+    async fn create_pull_request(
+        &self,
+        head: &str,
+        base: &str,
+        _: &str,
+        _: &str,
+    ) -> Result<PullRequest> {
+        let mut state = self.state.lock().unwrap();
+        let number = next_id();
+        let pull_request = PullRequest {
+            number,
+            html_url: format!("https://github.com/elba/index/pull/{}", number),
+            state: "open".to_owned(),
+            merged: false,
+            head: PullRequestRef {
+                branch: head.to_owned(),
+                sha: head.to_owned(),
+            },
+            base: PullRequestRef {
+                branch: base.to_owned(),
+                sha: base.to_owned(),
+            },
+        };
+        state.pull_requests.push(pull_request.clone());
+        Ok(pull_request)
+    }
+
+    async fn pull_request(&self, number: i64) -> Result<PullRequest> {
+        self.state
+            .lock()
+            .unwrap()
+            .pull_requests
+            .iter()
+            .find(|pull_request| pull_request.number == number)
+            .cloned()
+            .ok_or_else(|| Error::Github(format!("pull request {} not found", number)).into())
+    }
+
+    async fn branch_pull_request(&self, branch: &str) -> Result<Option<PullRequest>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .pull_requests
+            .iter()
+            .find(|pull_request| pull_request.state == "open" && pull_request.head.branch == branch)
+            .cloned())
+    }
+
+    async fn pull_request_approved(&self, number: i64) -> Result<bool> {
+        Ok(self.state.lock().unwrap().approved.contains(&number))
+    }
+
+    async fn commit_checks(&self, _: &str) -> Result<CheckState> {
+        Ok(self.state.lock().unwrap().checks)
+    }
+
+    async fn merge_pull_request(&self, number: i64, _: &str) -> Result<()> {
+        self.set_pull_request(number, |pull_request| {
+            merge_branch(number, &pull_request.base.branch, &pull_request.head.branch)?;
+            pull_request.state = "closed".to_owned();
+            pull_request.merged = true;
+            Ok(())
+        })
+    }
+
+    async fn close_pull_request(&self, number: i64) -> Result<()> {
+        self.set_pull_request(number, |pull_request| {
+            pull_request.state = "closed".to_owned();
+            Ok(())
+        })
+    }
+}
+
+/// Merge the head branch into the base branch of the index repository, which
+/// is a local bare repository in tests, failing on conflicts like Github does
+fn merge_branch(number: i64, base: &str, head: &str) -> Result<()> {
+    let url = CONFIG.index_repo_url.as_deref().unwrap_or_default();
+    let repo = Repository::open_bare(url.trim_start_matches("file://"))?;
+    let base_refname = format!("refs/heads/{}", base);
+    let base_commit = repo.find_reference(&base_refname)?.peel_to_commit()?;
+    let head_commit = repo
+        .find_reference(&format!("refs/heads/{}", head))?
+        .peel_to_commit()?;
+
+    let mut index = repo.merge_commits(&base_commit, &head_commit, None)?;
+    if index.has_conflicts() {
+        return Err(Error::Github(format!("pull request {} has conflicts", number)).into());
+    }
+    let tree = repo.find_tree(index.write_tree_to(&repo)?)?;
+    let sig = Signature::now("github", "noreply@github.com")?;
+    repo.commit(
+        Some(&base_refname),
+        &sig,
+        &sig,
+        &format!("Merge pull request #{} from {}", number, head),
+        &tree,
+        &[&base_commit, &head_commit],
+    )?;
+    Ok(())
+}
+
+fn now() -> DateTime<FixedOffset> {
+    Utc::now().with_timezone(&FixedOffset::east(0))
+}
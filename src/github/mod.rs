@@ -0,0 +1,1238 @@
+pub mod auth;
+#[cfg(test)]
+pub mod fake;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use failure::bail;
+use log::{debug, warn};
+use reqwest::{
+    header::{self, HeaderMap},
+    Client, RequestBuilder, Response, StatusCode, Url,
+};
+use serde::{
+    de::{DeserializeOwned, Error as _},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::json;
+use tokio::sync::RwLock;
+use tokio::time::delay_for;
+
+use crate::config::CONFIG;
+use crate::error::{Error, Result};
+
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The reactions API is still in preview
+const REACTIONS_PREVIEW: &str = "application/vnd.github.squirrel-girl-preview+json";
+
+/// The checks API is still in preview
+const CHECKS_PREVIEW: &str = "application/vnd.github.antiope-preview+json";
+
+/// How long to back off from a secondary rate limit that doesn't say how long,
+/// which is doubled on every retry up to `MAX_SECONDARY_RATE_LIMIT_DELAY`
+pub const SECONDARY_RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+pub const MAX_SECONDARY_RATE_LIMIT_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// Page size when listing comments, which is the maximum Github allows
+pub const COMMENTS_PER_PAGE: u32 = 100;
+
+/// Reviews of a publish pull request are few, so one page is enough
+const REVIEWS_PER_PAGE: u32 = 100;
+
+/// The Github API calls the bot relies on, so that the controller can be driven
+/// by the real Github or by a fake one in tests
+#[async_trait]
+pub trait GithubApi: Send + Sync {
+    /// Id of the user the bot is authenticated as
+    fn viewer_id(&self) -> i64;
+
+    /// List the comments updated since the date, on the index issue or on all
+    /// issues if commands are accepted on labelled issues
+    ///
+    /// Returns `None` when nothing has changed since the last call.
+    async fn issue_comments(
+        &self,
+        since: Option<DateTime<FixedOffset>>,
+    ) -> Result<Option<GithubResponse<Vec<Comment>>>>;
+
+    async fn query_user(&self, user_name: &str) -> Result<User>;
+
+    async fn update_comment(&self, comment_id: i64, body: String) -> Result<()>;
+
+    async fn create_comment(&self, issue_number: i64, body: String) -> Result<Comment>;
+
+    /// Names of the labels on the issue
+    async fn issue_labels(&self, issue_number: i64) -> Result<Vec<String>>;
+
+    /// React to the comment, returning the id of the reaction
+    async fn create_reaction(&self, comment_id: i64, reaction: Reaction) -> Result<i64>;
+
+    async fn delete_reaction(&self, comment_id: i64, reaction_id: i64) -> Result<()>;
+
+    /// Open a pull request in the index repository to merge `head` into `base`
+    async fn create_pull_request(
+        &self,
+        head: &str,
+        base: &str,
+        title: &str,
+        body: &str,
+    ) -> Result<PullRequest>;
+
+    async fn pull_request(&self, number: i64) -> Result<PullRequest>;
+
+    /// The open pull request from the branch of the index repository, if any
+    async fn branch_pull_request(&self, branch: &str) -> Result<Option<PullRequest>>;
+
+    /// Whether the pull request is approved by a reviewer and no reviewer
+    /// requests changes
+    async fn pull_request_approved(&self, number: i64) -> Result<bool>;
+
+    /// The state of the statuses and check runs on the commit
+    async fn commit_checks(&self, sha: &str) -> Result<CheckState>;
+
+    /// Merge the pull request, provided its head is still the commit
+    async fn merge_pull_request(&self, number: i64, sha: &str) -> Result<()>;
+
+    async fn close_pull_request(&self, number: i64) -> Result<()>;
+
+    /// The rate limit budget reported by the last response, if known
+    async fn rate_limit(&self) -> Option<RateLimit> {
+        None
+    }
+
+    /// List comments in endless loop until changes are found
+    ///
+    /// The polling slows down as the rate limit budget drains, so that it lasts
+    /// until the budget is reset.
+    async fn poll_issue_comments(
+        &self,
+        since: Option<DateTime<FixedOffset>>,
+    ) -> Result<GithubResponse<Vec<Comment>>> {
+        loop {
+            if let Some(resp) = self.issue_comments(since).await? {
+                return Ok(resp);
+            } else {
+                let interval = match self.rate_limit().await {
+                    Some(rate_limit) => poll_interval(&rate_limit, Utc::now()),
+                    None => POLL_INTERVAL,
+                };
+                delay_for(interval).await
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Github {
+    client: Client,
+    viewer_id: i64,
+    etags: RwLock<HashMap<String, String>>,
+    rate_limit: RwLock<Option<RateLimit>>,
+}
+
+impl Github {
+    pub async fn new() -> Result<Self> {
+        let mut github = Self {
+            client: Client::builder().build()?,
+            viewer_id: 0,
+            etags: RwLock::new(HashMap::new()),
+            rate_limit: RwLock::new(None),
+        };
+
+        // A Github App comments as its bot user, which can't query itself
+        let viewer_url = if auth::is_app() {
+            let slug = auth::app_slug().await?;
+            url::user(&format!("{}[bot]", slug))
+        } else {
+            url::authenticated_user()
+        };
+        let viewer: User = github
+            .send(
+                github
+                    .client
+                    .get(Url::parse(&viewer_url)?)
+                    .headers(headers()),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        github.viewer_id = viewer.id;
+
+        Ok(github)
+    }
+
+    /// Send the request authenticated, and wait and retry while it's rate limited
+    ///
+    /// Other responses forbidding the request fail with the message of Github.
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let request = request.build()?;
+        let mut retries = 0;
+        loop {
+            // Authenticate every attempt since the token may expire while waiting
+            let mut attempt = request
+                .try_clone()
+                .expect("requests to Github have no streaming body");
+            let token = auth::token().await?;
+            attempt.headers_mut().insert(
+                header::AUTHORIZATION,
+                format!("token {}", token).parse().unwrap(),
+            );
+            let resp = self.client.execute(attempt).await?;
+
+            if let Some(rate_limit) = RateLimit::from_headers(resp.headers()) {
+                debug!("Github rate limit: {}", rate_limit);
+                *self.rate_limit.write().await = Some(rate_limit);
+            }
+
+            let status = resp.status();
+            if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(resp);
+            }
+            // Secondary rate limits are only told from permission errors by the
+            // message
+            let headers = resp.headers().clone();
+            let message = resp.text().await?;
+            match rate_limit_delay(status, &headers, &message, retries, Utc::now()) {
+                Some(delay) => {
+                    warn!(
+                        "Github rate limit exceeded on {}, retrying in {}s",
+                        request.url(),
+                        delay.as_secs()
+                    );
+                    delay_for(delay).await;
+                    retries += 1;
+                }
+                None => bail!(Error::Github(message)),
+            }
+        }
+    }
+
+    /// Query a Github API V3 endpoint listing items, following the `Link`
+    /// header to merge all pages
+    ///
+    /// The ETAG header is used to prevent redundant query. Returns `None` when
+    /// the content is unchanged. The ETAG only covers the first page, so it is
+    /// only used when the last response fitted in a single page.
+    pub async fn query<T, Q>(
+        &self,
+        url: &str,
+        query: &Q,
+        per_page: u32,
+    ) -> Result<Option<GithubResponse<Vec<T>>>>
+    where
+        T: DeserializeOwned,
+        Q: Serialize,
+    {
+        let etag = self.etags.read().await.get(url).cloned();
+
+        let mut headers = headers();
+        if let Some(etag) = &etag {
+            headers.insert(header::IF_NONE_MATCH, etag.parse().unwrap());
+        }
+
+        let resp = self
+            .send(
+                self.client
+                    .get(Url::parse(url)?)
+                    .query(query)
+                    .query(&[("per_page", per_page)])
+                    .headers(headers),
+            )
+            .await?;
+
+        match resp.status() {
+            StatusCode::OK => (),
+            StatusCode::NOT_MODIFIED => return Ok(None),
+            _ => {
+                let text = resp.text().await?;
+                return Err(Error::Github(text).into());
+            }
+        }
+
+        let etag = String::from_utf8(resp.headers().get(header::ETAG).unwrap().as_ref().to_vec())?;
+        let date = DateTime::parse_from_rfc2822(&String::from_utf8(
+            resp.headers().get(header::DATE).unwrap().as_ref().to_vec(),
+        )?)?;
+        let mut next_url = next_page_url(resp.headers());
+        if next_url.is_none() {
+            self.etags.write().await.insert(url.to_string(), etag);
+        } else {
+            self.etags.write().await.remove(url);
+        }
+        let mut val: Vec<T> = resp.json().await?;
+
+        // The next page links carry the query and `per_page` already
+        while let Some(url) = next_url {
+            let resp = self
+                .send(self.client.get(Url::parse(&url)?).headers(headers()))
+                .await?;
+            if resp.status() != StatusCode::OK {
+                let text = resp.text().await?;
+                return Err(Error::Github(text).into());
+            }
+            next_url = next_page_url(resp.headers());
+            val.extend(resp.json::<Vec<T>>().await?);
+        }
+
+        Ok(Some(GithubResponse { val, date }))
+    }
+}
+
+#[async_trait]
+impl GithubApi for Github {
+    fn viewer_id(&self) -> i64 {
+        self.viewer_id
+    }
+
+    async fn issue_comments(
+        &self,
+        since: Option<DateTime<FixedOffset>>,
+    ) -> Result<Option<GithubResponse<Vec<Comment>>>> {
+        let url = match (&CONFIG.index_issue_label, CONFIG.index_issue_number) {
+            (Some(_), _) => url::repo_issue_comments(&CONFIG.index_repo_name),
+            (None, Some(issue_number)) => {
+                url::issue_comments(&CONFIG.index_repo_name, issue_number)
+            }
+            (None, None) => bail!(Error::MissingConfig("INDEX_ISSUE_NUMBER")),
+        };
+        let mut query = vec![
+            ("sort", "created".to_owned()),
+            ("direction", "asc".to_owned()),
+        ];
+        if let Some(since) = since {
+            query.push(("since", since.to_rfc3339()));
+        }
+        self.query(&url, &query, COMMENTS_PER_PAGE).await
+    }
+
+    async fn query_user(&self, user_name: &str) -> Result<User> {
+        let resp = self
+            .send(
+                self.client
+                    .get(Url::parse(&url::user(user_name))?)
+                    .headers(headers()),
+            )
+            .await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Err(Error::UserNotFound(user_name.to_string()).into());
+        }
+        Ok(resp.error_for_status()?.json().await?)
+    }
+
+    async fn update_comment(&self, comment_id: i64, body: String) -> Result<()> {
+        self.send(
+            self.client
+                .patch(Url::parse(&url::issue_comment(
+                    &CONFIG.index_repo_name,
+                    comment_id,
+                ))?)
+                .headers(headers())
+                .json(&json!({ "body": body })),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    async fn create_comment(&self, issue_number: i64, body: String) -> Result<Comment> {
+        Ok(self
+            .send(
+                self.client
+                    .post(Url::parse(&url::issue_comments(
+                        &CONFIG.index_repo_name,
+                        issue_number,
+                    ))?)
+                    .headers(headers())
+                    .json(&json!({ "body": body })),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn issue_labels(&self, issue_number: i64) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Issue {
+            labels: Vec<Label>,
+        }
+        #[derive(Deserialize)]
+        struct Label {
+            name: String,
+        }
+
+        let issue: Issue = self
+            .send(
+                self.client
+                    .get(Url::parse(&url::issue(
+                        &CONFIG.index_repo_name,
+                        issue_number,
+                    ))?)
+                    .headers(headers()),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(issue.labels.into_iter().map(|label| label.name).collect())
+    }
+
+    async fn create_reaction(&self, comment_id: i64, reaction: Reaction) -> Result<i64> {
+        #[derive(Deserialize)]
+        struct Created {
+            id: i64,
+        }
+
+        let created: Created = self
+            .send(
+                self.client
+                    .post(Url::parse(&url::issue_comment_reactions(
+                        &CONFIG.index_repo_name,
+                        comment_id,
+                    ))?)
+                    .headers(headers())
+                    .header(header::ACCEPT, REACTIONS_PREVIEW)
+                    .json(&json!({ "content": reaction.content() })),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(created.id)
+    }
+
+    async fn delete_reaction(&self, comment_id: i64, reaction_id: i64) -> Result<()> {
+        self.send(
+            self.client
+                .delete(Url::parse(&url::issue_comment_reaction(
+                    &CONFIG.index_repo_name,
+                    comment_id,
+                    reaction_id,
+                ))?)
+                .headers(headers())
+                .header(header::ACCEPT, REACTIONS_PREVIEW),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    async fn create_pull_request(
+        &self,
+        head: &str,
+        base: &str,
+        title: &str,
+        body: &str,
+    ) -> Result<PullRequest> {
+        Ok(self
+            .send(
+                self.client
+                    .post(Url::parse(&url::pull_requests(&CONFIG.index_repo_name))?)
+                    .headers(headers())
+                    .json(&json!({
+                        "head": head,
+                        "base": base,
+                        "title": title,
+                        "body": body,
+                    })),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn pull_request(&self, number: i64) -> Result<PullRequest> {
+        Ok(self
+            .send(
+                self.client
+                    .get(Url::parse(&url::pull_request(
+                        &CONFIG.index_repo_name,
+                        number,
+                    ))?)
+                    .headers(headers()),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn branch_pull_request(&self, branch: &str) -> Result<Option<PullRequest>> {
+        // Github filters heads by `owner:branch`
+        let owner = CONFIG.index_repo_name.split('/').next().unwrap_or_default();
+        let pull_requests: Vec<PullRequest> = self
+            .send(
+                self.client
+                    .get(Url::parse(&url::pull_requests(&CONFIG.index_repo_name))?)
+                    .query(&[
+                        ("state", "open".to_owned()),
+                        ("head", format!("{}:{}", owner, branch)),
+                    ])
+                    .headers(headers()),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(pull_requests.into_iter().next())
+    }
+
+    async fn pull_request_approved(&self, number: i64) -> Result<bool> {
+        let reviews: Vec<Review> = self
+            .send(
+                self.client
+                    .get(Url::parse(&url::pull_request_reviews(
+                        &CONFIG.index_repo_name,
+                        number,
+                    ))?)
+                    .query(&[("per_page", REVIEWS_PER_PAGE)])
+                    .headers(headers()),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(is_approved(&reviews))
+    }
+
+    async fn commit_checks(&self, sha: &str) -> Result<CheckState> {
+        #[derive(Deserialize)]
+        struct CombinedStatus {
+            state: String,
+            total_count: u64,
+        }
+        #[derive(Deserialize)]
+        struct CheckRuns {
+            check_runs: Vec<CheckRun>,
+        }
+        #[derive(Deserialize)]
+        struct CheckRun {
+            status: String,
+            conclusion: Option<String>,
+        }
+
+        let status: CombinedStatus = self
+            .send(
+                self.client
+                    .get(Url::parse(&url::commit_status(
+                        &CONFIG.index_repo_name,
+                        sha,
+                    ))?)
+                    .headers(headers()),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let check_runs: CheckRuns = self
+            .send(
+                self.client
+                    .get(Url::parse(&url::commit_check_runs(
+                        &CONFIG.index_repo_name,
+                        sha,
+                    ))?)
+                    .headers(headers())
+                    .header(header::ACCEPT, CHECKS_PREVIEW),
+            )
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // The combined status is pending when there are no statuses at all
+        let mut states = Vec::new();
+        if status.total_count > 0 {
+            states.push(match status.state.as_str() {
+                "success" => CheckState::Success,
+                "pending" => CheckState::Pending,
+                _ => CheckState::Failure,
+            });
+        }
+        for run in check_runs.check_runs {
+            states.push(match (run.status.as_str(), run.conclusion.as_deref()) {
+                ("completed", Some("success"))
+                | ("completed", Some("neutral"))
+                | ("completed", Some("skipped")) => CheckState::Success,
+                ("completed", _) => CheckState::Failure,
+                _ => CheckState::Pending,
+            });
+        }
+        Ok(combine_checks(states))
+    }
+
+    async fn merge_pull_request(&self, number: i64, sha: &str) -> Result<()> {
+        self.send(
+            self.client
+                .put(Url::parse(&url::pull_request_merge(
+                    &CONFIG.index_repo_name,
+                    number,
+                ))?)
+                .headers(headers())
+                .json(&json!({ "sha": sha })),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    async fn close_pull_request(&self, number: i64) -> Result<()> {
+        self.send(
+            self.client
+                .patch(Url::parse(&url::pull_request(
+                    &CONFIG.index_repo_name,
+                    number,
+                ))?)
+                .headers(headers())
+                .json(&json!({ "state": "closed" })),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    async fn rate_limit(&self) -> Option<RateLimit> {
+        *self.rate_limit.read().await
+    }
+}
+
+/// Headers of every request, except the authorization which is added by `send`
+fn headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+    headers.insert(header::USER_AGENT, CONFIG.bot_name.parse().unwrap());
+    headers
+}
+
+/// Find the url of the next page in the `Link` header, which looks like
+/// `<https://api.github.com/...?page=2>; rel="next", <...>; rel="last"`
+fn next_page_url(headers: &HeaderMap) -> Option<String> {
+    let link = headers.get(header::LINK)?.to_str().ok()?;
+    parse_next_link(link)
+}
+
+fn parse_next_link(link: &str) -> Option<String> {
+    link.split(',').find_map(|part| {
+        let mut segments = part.split(';');
+        let url = segments.next()?.trim();
+        let is_next = segments.any(|param| param.trim() == r#"rel="next""#);
+        if is_next && url.starts_with('<') && url.ends_with('>') {
+            Some(url[1..url.len() - 1].to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// The primary rate limit budget of the authenticated user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset: DateTime<Utc>,
+}
+
+impl RateLimit {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        Some(RateLimit {
+            limit: header_value(headers, "x-ratelimit-limit")?,
+            remaining: header_value(headers, "x-ratelimit-remaining")?,
+            reset: Utc.timestamp(header_value(headers, "x-ratelimit-reset")?, 0),
+        })
+    }
+}
+
+impl std::fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} requests remaining, reset at {}",
+            self.remaining, self.limit, self.reset
+        )
+    }
+}
+
+fn header_value<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Spread the remaining budget evenly over the time until it's reset
+fn poll_interval(rate_limit: &RateLimit, now: DateTime<Utc>) -> Duration {
+    let until_reset = (rate_limit.reset - now).to_std().unwrap_or_default();
+    let interval = until_reset / cmp::max(rate_limit.remaining, 1) as u32;
+    cmp::max(interval, POLL_INTERVAL)
+}
+
+/// How long to wait before retrying a response, or `None` if it is not rate
+/// limited
+///
+/// Secondary rate limits come with `Retry-After`, or are otherwise told from a
+/// plain permission error by the message and backed off from longer on every
+/// retry, while an exhausted primary rate limit has to wait until the budget is
+/// reset.
+fn rate_limit_delay(
+    status: StatusCode,
+    headers: &HeaderMap,
+    message: &str,
+    retries: u32,
+    now: DateTime<Utc>,
+) -> Option<Duration> {
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    if let Some(secs) = header_value(headers, "retry-after") {
+        return Some(Duration::from_secs(secs));
+    }
+    match RateLimit::from_headers(headers) {
+        Some(rate_limit) if rate_limit.remaining == 0 => {
+            Some((rate_limit.reset - now).to_std().unwrap_or_default() + Duration::from_secs(1))
+        }
+        _ if status == StatusCode::TOO_MANY_REQUESTS || is_secondary_rate_limit(message) => {
+            Some(cmp::min(
+                SECONDARY_RATE_LIMIT_DELAY * 2u32.saturating_pow(retries),
+                MAX_SECONDARY_RATE_LIMIT_DELAY,
+            ))
+        }
+        // A plain permission error
+        _ => None,
+    }
+}
+
+/// Whether the message of a 403 response is about a secondary rate limit, which
+/// used to be called abuse detection
+fn is_secondary_rate_limit(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("secondary rate limit") || message.contains("abuse detection")
+}
+
+/// Reactions showing the state of a command at a glance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reaction {
+    /// 👀 The command is accepted
+    Eyes,
+    /// 🚀 The command is done
+    Rocket,
+    /// 😕 The command can't be understood
+    Confused,
+    /// 👎 The command failed
+    ThumbsDown,
+}
+
+impl Reaction {
+    pub fn content(self) -> &'static str {
+        match self {
+            Reaction::Eyes => "eyes",
+            Reaction::Rocket => "rocket",
+            Reaction::Confused => "confused",
+            Reaction::ThumbsDown => "-1",
+        }
+    }
+}
+
+/// Overall state of the statuses and check runs on a commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckState {
+    Pending,
+    Success,
+    Failure,
+}
+
+/// Any failure fails the commit, and it only succeeds once all are done
+fn combine_checks(states: Vec<CheckState>) -> CheckState {
+    if states.contains(&CheckState::Failure) {
+        CheckState::Failure
+    } else if states.contains(&CheckState::Pending) {
+        CheckState::Pending
+    } else {
+        CheckState::Success
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Review {
+    user: User,
+    state: String,
+}
+
+/// Only the latest review of each reviewer counts, ignoring comments
+fn is_approved(reviews: &[Review]) -> bool {
+    let mut latest = HashMap::new();
+    for review in reviews {
+        if review.state == "APPROVED" || review.state == "CHANGES_REQUESTED" {
+            latest.insert(review.user.id, review.state.as_str());
+        }
+    }
+    latest.values().any(|state| *state == "APPROVED")
+        && !latest.values().any(|state| *state == "CHANGES_REQUESTED")
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PullRequest {
+    pub number: i64,
+    pub html_url: String,
+    /// `open` or `closed`
+    pub state: String,
+    #[serde(default)]
+    pub merged: bool,
+    pub head: PullRequestRef,
+    pub base: PullRequestRef,
+}
+
+/// The head or the base branch of a pull request
+#[derive(Debug, Deserialize, Clone)]
+pub struct PullRequestRef {
+    #[serde(rename = "ref")]
+    pub branch: String,
+    pub sha: String,
+}
+
+#[derive(Debug)]
+pub struct GithubResponse<T> {
+    pub val: T,
+    pub date: DateTime<FixedOffset>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct User {
+    pub id: i64,
+    #[serde(rename = "login")]
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Comment {
+    pub id: i64,
+    pub user: User,
+    pub body: String,
+    pub created_at: DateTime<FixedOffset>,
+    #[serde(rename = "issue_url", deserialize_with = "deserialize_issue_number")]
+    pub issue_number: i64,
+}
+
+/// Take the issue number from the end of an issue url like
+/// `https://api.github.com/repos/elba/index/issues/1`
+fn deserialize_issue_number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<i64, D::Error> {
+    let issue_url = String::deserialize(deserializer)?;
+    issue_url
+        .rsplit('/')
+        .next()
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(|| D::Error::custom(format!("invalid issue url `{}`", issue_url)))
+}
+
+pub mod url {
+    pub fn user_profile(user_name: &str) -> String {
+        format!("https://github.com/{}", user_name)
+    }
+
+    pub fn user(user_name: &str) -> String {
+        format!("https://api.github.com/users/{}", user_name)
+    }
+
+    pub fn authenticated_user() -> String {
+        format!("https://api.github.com/user")
+    }
+
+    pub fn issue(repo: &str, issue_number: i64) -> String {
+        format!(
+            "https://api.github.com/repos/{}/issues/{}",
+            repo, issue_number
+        )
+    }
+
+    pub fn repo_issue_comments(repo: &str) -> String {
+        format!("https://api.github.com/repos/{}/issues/comments", repo)
+    }
+
+    pub fn issue_comments(repo: &str, issue_number: i64) -> String {
+        format!(
+            "https://api.github.com/repos/{}/issues/{}/comments",
+            repo, issue_number
+        )
+    }
+
+    pub fn issue_comment(repo: &str, comment_id: i64) -> String {
+        format!(
+            "https://api.github.com/repos/{}/issues/comments/{}",
+            repo, comment_id
+        )
+    }
+
+    pub fn issue_comment_reactions(repo: &str, comment_id: i64) -> String {
+        format!(
+            "https://api.github.com/repos/{}/issues/comments/{}/reactions",
+            repo, comment_id
+        )
+    }
+
+    pub fn issue_comment_reaction(repo: &str, comment_id: i64, reaction_id: i64) -> String {
+        format!(
+            "https://api.github.com/repos/{}/issues/comments/{}/reactions/{}",
+            repo, comment_id, reaction_id
+        )
+    }
+
+    pub fn pull_requests(repo: &str) -> String {
+        format!("https://api.github.com/repos/{}/pulls", repo)
+    }
+
+    pub fn pull_request(repo: &str, number: i64) -> String {
+        format!("https://api.github.com/repos/{}/pulls/{}", repo, number)
+    }
+
+    pub fn pull_request_reviews(repo: &str, number: i64) -> String {
+        format!(
+            "https://api.github.com/repos/{}/pulls/{}/reviews",
+            repo, number
+        )
+    }
+
+    pub fn pull_request_merge(repo: &str, number: i64) -> String {
+        format!(
+            "https://api.github.com/repos/{}/pulls/{}/merge",
+            repo, number
+        )
+    }
+
+    pub fn commit_status(repo: &str, sha: &str) -> String {
+        format!(
+            "https://api.github.com/repos/{}/commits/{}/status",
+            repo, sha
+        )
+    }
+
+    pub fn commit_check_runs(repo: &str, sha: &str) -> String {
+        format!(
+            "https://api.github.com/repos/{}/commits/{}/check-runs",
+            repo, sha
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+    use crate::testing;
+
+    /// Serve the responses given the url of the fake API one per connection,
+    /// returning the url and the requests it receives
+    fn fake_api(
+        responses: impl FnOnce(&str) -> Vec<String> + Send + 'static,
+    ) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let api_url = url.clone();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses(&api_url) {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..len]);
+                }
+                requests.push(String::from_utf8(request).unwrap().to_lowercase());
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    fn response(status: &str, headers: &[String], body: &str) -> String {
+        let mut response = format!(
+            "HTTP/1.1 {}\r\ndate: Sun, 01 Mar 2020 12:00:00 GMT\r\n\
+             content-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n",
+            status,
+            body.len()
+        );
+        for header in headers {
+            response += &format!("{}\r\n", header);
+        }
+        response + "\r\n" + body
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_query_pages() {
+        let _lock = testing::lock();
+        let (url, server) = fake_api(|url| {
+            let first_page = response(
+                "200 OK",
+                &[
+                    r#"etag: "paged""#.to_owned(),
+                    format!(r#"link: <{}/paged?page=2>; rel="next""#, url),
+                ],
+                "[1, 2]",
+            );
+            let last_page = response("200 OK", &[], "[3]");
+            let single_page = response("200 OK", &[r#"etag: "single""#.to_owned()], "[4]");
+            let not_modified = response("304 Not Modified", &[], "");
+            vec![
+                first_page.clone(),
+                last_page.clone(),
+                first_page,
+                last_page,
+                single_page,
+                not_modified,
+            ]
+        });
+        let github = Github {
+            client: Client::new(),
+            viewer_id: 0,
+            etags: RwLock::new(HashMap::new()),
+            rate_limit: RwLock::new(None),
+        };
+        let query = [("since", "2020-03-01T00:00:00Z")];
+
+        // All pages are merged, and the ETAG of the first page is not used
+        // again as it doesn't cover the others
+        let paged_url = format!("{}/paged", url);
+        for _ in 0..2 {
+            let resp = github.query::<i64, _>(&paged_url, &query, 2).await.unwrap();
+            assert_eq!(resp.unwrap().val, [1, 2, 3]);
+        }
+
+        // The ETAG of a single page is used to skip unchanged content
+        let single_url = format!("{}/single", url);
+        let resp = github
+            .query::<i64, _>(&single_url, &query, 2)
+            .await
+            .unwrap();
+        assert_eq!(resp.unwrap().val, [4]);
+        let resp = github
+            .query::<i64, _>(&single_url, &query, 2)
+            .await
+            .unwrap();
+        assert!(resp.is_none());
+
+        let requests = server.join().unwrap();
+        let request_lines: Vec<_> = requests
+            .iter()
+            .map(|request| request.lines().next().unwrap())
+            .collect();
+        assert_eq!(
+            request_lines,
+            [
+                "get /paged?since=2020-03-01t00%3a00%3a00z&per_page=2 http/1.1",
+                "get /paged?page=2 http/1.1",
+                "get /paged?since=2020-03-01t00%3a00%3a00z&per_page=2 http/1.1",
+                "get /paged?page=2 http/1.1",
+                "get /single?since=2020-03-01t00%3a00%3a00z&per_page=2 http/1.1",
+                "get /single?since=2020-03-01t00%3a00%3a00z&per_page=2 http/1.1",
+            ]
+        );
+        for request in &requests[..5] {
+            assert!(!request.contains("if-none-match"), "{}", request);
+        }
+        assert!(
+            requests[5].contains(r#"if-none-match: "single""#),
+            "{}",
+            requests[5]
+        );
+    }
+
+    #[test]
+    fn test_parse_next_link() {
+        assert_eq!(
+            parse_next_link(
+                r#"<https://api.github.com/repositories/1/issues/1/comments?per_page=100&page=2>; rel="next", <https://api.github.com/repositories/1/issues/1/comments?per_page=100&page=5>; rel="last""#
+            ),
+            Some(
+                "https://api.github.com/repositories/1/issues/1/comments?per_page=100&page=2"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            parse_next_link(
+                r#"<https://api.github.com/repositories/1/issues/1/comments?page=1>; rel="prev", <https://api.github.com/repositories/1/issues/1/comments?page=1>; rel="first""#
+            ),
+            None
+        );
+        assert_eq!(parse_next_link(""), None);
+    }
+
+    #[test]
+    fn test_deserialize_comment() {
+        let comment: Comment = serde_json::from_str(
+            r#"{
+                "id": 42,
+                "user": { "id": 7, "login": "someone" },
+                "body": "@elba-bot /status",
+                "created_at": "2020-03-01T12:00:00Z",
+                "issue_url": "https://api.github.com/repos/elba/index/issues/1347"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(comment.issue_number, 1347);
+        assert_eq!(comment.user.name, "someone");
+
+        assert!(serde_json::from_str::<Comment>(
+            r#"{
+                "id": 42,
+                "user": { "id": 7, "login": "someone" },
+                "body": "",
+                "created_at": "2020-03-01T12:00:00Z",
+                "issue_url": "https://api.github.com/repos/elba/index/issues/"
+            }"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_is_approved() {
+        let review = |user_id, state: &str| Review {
+            user: User {
+                id: user_id,
+                name: format!("user{}", user_id),
+            },
+            state: state.to_owned(),
+        };
+
+        assert!(!is_approved(&[]));
+        assert!(!is_approved(&[review(1, "COMMENTED")]));
+        assert!(is_approved(&[
+            review(1, "APPROVED"),
+            review(1, "COMMENTED")
+        ]));
+        assert!(!is_approved(&[
+            review(1, "APPROVED"),
+            review(2, "CHANGES_REQUESTED")
+        ]));
+        assert!(is_approved(&[
+            review(1, "CHANGES_REQUESTED"),
+            review(1, "APPROVED")
+        ]));
+    }
+
+    #[test]
+    fn test_combine_checks() {
+        use CheckState::*;
+
+        assert_eq!(combine_checks(vec![]), Success);
+        assert_eq!(combine_checks(vec![Success, Pending]), Pending);
+        assert_eq!(combine_checks(vec![Pending, Failure, Success]), Failure);
+        assert_eq!(combine_checks(vec![Success, Success]), Success);
+    }
+
+    fn rate_limit_headers(remaining: u64, reset: i64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-limit", "5000".parse().unwrap());
+        headers.insert(
+            "x-ratelimit-remaining",
+            remaining.to_string().parse().unwrap(),
+        );
+        headers.insert("x-ratelimit-reset", reset.to_string().parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_poll_interval() {
+        let now = Utc.timestamp(1_000_000, 0);
+        let rate_limit =
+            |remaining| RateLimit::from_headers(&rate_limit_headers(remaining, 1_003_600)).unwrap();
+
+        assert_eq!(
+            poll_interval(&rate_limit(4000), now),
+            Duration::from_millis(900)
+        );
+        assert_eq!(
+            poll_interval(&rate_limit(10), now),
+            Duration::from_secs(360)
+        );
+        assert_eq!(
+            poll_interval(&rate_limit(0), now),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            poll_interval(&rate_limit(100), Utc.timestamp(1_003_700, 0)),
+            POLL_INTERVAL
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_delay() {
+        let now = Utc.timestamp(1_000_000, 0);
+        let forbidden = r#"{"message":"Resource not accessible by integration"}"#;
+        let secondary = r#"{"message":"You have exceeded a secondary rate limit. Please wait a few minutes before you try again."}"#;
+
+        assert_eq!(
+            rate_limit_delay(
+                StatusCode::OK,
+                &rate_limit_headers(0, 1_000_060),
+                "",
+                0,
+                now
+            ),
+            None
+        );
+        assert_eq!(
+            rate_limit_delay(
+                StatusCode::FORBIDDEN,
+                &rate_limit_headers(0, 1_000_060),
+                "",
+                0,
+                now
+            ),
+            Some(Duration::from_secs(61))
+        );
+        assert_eq!(
+            rate_limit_delay(
+                StatusCode::FORBIDDEN,
+                &rate_limit_headers(10, 1_000_060),
+                forbidden,
+                0,
+                now
+            ),
+            None
+        );
+
+        let mut headers = rate_limit_headers(10, 1_000_060);
+        headers.insert("retry-after", "30".parse().unwrap());
+        assert_eq!(
+            rate_limit_delay(StatusCode::FORBIDDEN, &headers, secondary, 3, now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            rate_limit_delay(StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new(), "", 0, now),
+            Some(SECONDARY_RATE_LIMIT_DELAY)
+        );
+
+        // A secondary rate limit without `Retry-After` is backed off from
+        // longer on every retry
+        let headers = rate_limit_headers(10, 1_000_060);
+        let delays: Vec<_> = (0..6)
+            .map(|retries| {
+                rate_limit_delay(StatusCode::FORBIDDEN, &headers, secondary, retries, now)
+                    .unwrap()
+                    .as_secs()
+            })
+            .collect();
+        assert_eq!(delays, [60, 120, 240, 480, 900, 900]);
+        assert_eq!(
+            rate_limit_delay(
+                StatusCode::FORBIDDEN,
+                &headers,
+                "You have triggered an abuse detection mechanism.",
+                0,
+                now
+            ),
+            Some(SECONDARY_RATE_LIMIT_DELAY)
+        );
+    }
+}
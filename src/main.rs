@@ -3,19 +3,23 @@
 #![feature(specialization)]
 #![feature(try_blocks)]
 
+mod cli;
 mod config;
 mod controller;
 mod database;
 mod error;
+mod fsck;
 mod github;
+mod rebuild;
+#[cfg(test)]
+mod testing;
+mod verify;
+mod webhook;
 mod workspace;
 
-use std::sync::Arc;
-use std::time::Duration;
+use structopt::StructOpt;
 
-use log::{error, info};
-
-use crate::controller::Controller;
+use crate::cli::{Cli, Subcommand};
 use crate::error::Result;
 
 #[tokio::main]
@@ -23,20 +27,6 @@ async fn main() -> Result<()> {
     dotenv::dotenv()?;
     env_logger::init();
 
-    loop {
-        info!("Controller started");
-        let res = tokio::spawn(async {
-            let res: Result<_> = try {
-                let controller = Arc::new(Controller::new().await?);
-                controller.run().await?;
-            };
-            res
-        })
-        .await?;
-
-        if let Err(err) = res {
-            error!("Controller failure: {}", err);
-            tokio::time::delay_for(Duration::from_secs(5)).await;
-        }
-    }
+    let subcommand = Cli::from_args().subcommand.unwrap_or(Subcommand::Serve);
+    cli::run(subcommand).await
 }
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use failure::bail;
+use log::{info, warn};
+
+use crate::config::CONFIG;
+use crate::database::{self, Database};
+use crate::error::{Error, Result};
+use crate::fsck::PackageKey;
+use crate::github;
+use crate::workspace::{self, Workspace};
+
+/// Rebuild the database at `DB_PATH` from the index and the store
+///
+/// Packages are reconstructed from the index entries and the manifests in
+/// their tarballs, while the publishers and the package directories are
+/// recovered from the messages of the commits publishing them, or else the
+/// publishers are assumed to be `assume_publisher`.
+/// Namespaces are then owned by their publishers, so owners changed by
+/// commands are lost.
+///
+/// Nothing is written unless the whole database is rebuilt.
+pub fn run(assume_publisher: Option<github::User>) -> Result<()> {
+    let workspace = Workspace::new()?;
+    let database = Database::open(&CONFIG.db_path)?;
+    let assume_publisher = assume_publisher.map(|user| database::User {
+        id: user.id,
+        name: user.name,
+    });
+    database.transaction(|database| rebuild(&workspace, database, assume_publisher.as_ref()))
+}
+
+fn rebuild(
+    workspace: &Workspace,
+    database: &Database,
+    assume_publisher: Option<&database::User>,
+) -> Result<()> {
+    if !database.query_package(None)?.is_empty() {
+        bail!(Error::DatabaseNotEmpty);
+    }
+
+    info!("Recovering publishers from commit history");
+    let mut publishes: HashMap<PackageKey, (database::User, Option<String>)> = HashMap::new();
+    let messages = workspace
+        .store
+        .commit_messages()?
+        .into_iter()
+        .chain(workspace.index.commit_messages()?);
+    for message in messages {
+        if let Some((name, version, publisher, path)) =
+            workspace::parse_publish_commit_msg(&message)
+        {
+            let key = PackageKey {
+                group: name.normalized_group().to_string(),
+                name: name.normalized_name().to_string(),
+                version,
+            };
+            publishes.insert(key, (publisher, path));
+        }
+    }
+
+    info!("Recovering packages from index entries");
+    let mut unknown_publishers = 0;
+    for entry in workspace.index.load_all_entries()? {
+        let key = PackageKey::from_entry(&entry);
+        let (publisher, path) = match (publishes.get(&key), assume_publisher) {
+            (Some((publisher, path)), _) => (publisher, path.clone()),
+            (None, Some(publisher)) => {
+                println!("Assuming {} is published by @{}", key, publisher.name);
+                (publisher, None)
+            }
+            (None, None) => {
+                warn!("Publisher of {} is unknown", key);
+                unknown_publishers += 1;
+                continue;
+            }
+        };
+
+        let manifest = workspace.store.load_manifest(&entry.name, &entry.version)?;
+        if manifest.is_none() {
+            warn!("Tarball of {} is missing from store", key);
+        }
+        let package = manifest.as_ref().map(|manifest| &manifest.package);
+
+        database.insert_user(publisher.clone())?;
+        database.insert_package(database::Package {
+            group: key.group.clone(),
+            name: key.name.clone(),
+            version: key.version.clone(),
+            description: package.and_then(|package| package.description.clone()),
+            homepage: package.and_then(|package| package.homepage.clone()),
+            repository: package.and_then(|package| package.repository.clone()),
+            user_id: publisher.id,
+            yanked: entry.yanked,
+            path,
+        })?;
+        println!("Recovered {} published by @{}", key, publisher.name);
+    }
+    database.seed_namespace_owners()?;
+
+    if unknown_publishers > 0 {
+        bail!(Error::UnknownPublishers(unknown_publishers));
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! Fixtures shared by the tests, with local bare repositories as the index and
+//! the store
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use git2::{Repository, Signature};
+use lazy_static::lazy_static;
+use tempdir::TempDir;
+
+pub const BOT_NAME: &str = "elba-bot";
+
+lazy_static! {
+    /// `CONFIG` is loaded once per process, so all tests share the same setup
+    pub static ref ROOT: PathBuf = setup();
+    static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Set up the fixtures, and run the tests using them one at a time since they
+/// share the repositories and the database
+pub fn lock() -> MutexGuard<'static, ()> {
+    lazy_static::initialize(&ROOT);
+    // A failing test poisons the lock, which shouldn't fail the others
+    LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Create the repositories and point `CONFIG` at them
+fn setup() -> PathBuf {
+    let root = TempDir::new("elba-bot-test").unwrap().into_path();
+
+    // The index has a default branch other than master, which the bot detects
+    let index_url = init_bare_repo(
+        &root.join("index.git"),
+        "main",
+        &[("README.TEMPLATE", "# Index\n\n{#package-list#}")],
+    );
+    let store_url = init_bare_repo(&root.join("store.git"), "master", &[(".gitkeep", "")]);
+    init_package_repo(&root.join("pkg"), "test/pkg", "0.1.0");
+
+    let vars = [
+        ("DB_PATH", root.join("bot.db").display().to_string()),
+        ("BOT_NAME", BOT_NAME.to_owned()),
+        ("ACCESS_TOKEN", String::new()),
+        ("INDEX_REPO_NAME", "elba/index".to_owned()),
+        ("INDEX_REPO_URL", index_url),
+        ("INDEX_ISSUE_NUMBER", "1".to_owned()),
+        ("INDEX_ISSUE_LABEL", "package".to_owned()),
+        ("INDEX_CHECKOUT", root.join("index").display().to_string()),
+        ("STORE_BACKEND", "git".to_owned()),
+        ("STORE_MAX_SIZE", "1048576".to_owned()),
+        ("STORE_REPO_NAME", "elba/store".to_owned()),
+        ("STORE_REPO_URL", store_url),
+        ("STORE_CHECKOUT", root.join("store").display().to_string()),
+        (
+            "STORE_URL_PREFIX",
+            format!("file://{}", root.join("store").display()),
+        ),
+    ];
+    for (key, val) in &vars {
+        env::set_var(key, val);
+    }
+
+    root
+}
+
+/// Create a bare repository with an initial commit of the files on the
+/// default branch
+pub fn init_bare_repo(path: &Path, branch: &str, files: &[(&str, &str)]) -> String {
+    let repo = Repository::init_bare(path).unwrap();
+    let refname = format!("refs/heads/{}", branch);
+    repo.set_head(&refname).unwrap();
+    let mut tree = repo.treebuilder(None).unwrap();
+    for (name, content) in files {
+        let blob = repo.blob(content.as_bytes()).unwrap();
+        tree.insert(name, blob, 0o100644).unwrap();
+    }
+    let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+    let sig = Signature::now("test", "test@example.com").unwrap();
+    repo.commit(Some(&refname), &sig, &sig, "Initial commit", &tree, &[])
+        .unwrap();
+    format!("file://{}", path.display())
+}
+
+/// Create the branch in the bare repository at the head of `base`
+pub fn create_branch(path: &Path, branch: &str, base: &str) {
+    let repo = Repository::open_bare(path).unwrap();
+    let commit = repo
+        .find_reference(&format!("refs/heads/{}", base))
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    repo.branch(branch, &commit, true).unwrap();
+}
+
+/// Create a repository containing an elba package
+pub fn init_package_repo(path: &Path, name: &str, version: &str) {
+    fs::create_dir_all(path.join("src")).unwrap();
+    fs::write(
+        path.join("elba.toml"),
+        format!(
+            "[package]\nname = \"{}\"\nversion = \"{}\"\nauthors = [\"test\"]\n\n\
+             [targets.lib]\nmods = [\"Pkg\"]\n",
+            name, version
+        ),
+    )
+    .unwrap();
+    fs::write(path.join("src").join("Pkg.idr"), "module Pkg\n").unwrap();
+
+    let repo = Repository::init(path).unwrap();
+    let mut index = repo.index().unwrap();
+    index
+        .add_all(&["*"], git2::IndexAddOption::DEFAULT, None)
+        .unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = Signature::now("test", "test@example.com").unwrap();
+    repo.commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[])
+        .unwrap();
+}
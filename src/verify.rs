@@ -0,0 +1,28 @@
+use std::path::Path;
+
+use failure::bail;
+
+use crate::error::{Error, Result};
+use crate::workspace::{Index, Verifier};
+
+/// Check that every commit changing metafiles in the index is signed by the
+/// key of the bot, given as its SSH public key or OpenPGP certificate, and
+/// print the ones that are not
+///
+/// Commits made before signing was enabled are reported as well, so that they
+/// can be reviewed once and skipped with `since` afterwards.
+pub fn run(public_key: &Path, since: Option<&str>) -> Result<()> {
+    let verifier = Verifier::new(public_key)?;
+    let index = Index::clone(None)?;
+
+    let unsigned = index.unsigned_metafile_commits(&verifier, since)?;
+    for commit in &unsigned {
+        println!("{}", commit);
+    }
+    println!("{} unsigned commits found", unsigned.len());
+
+    if !unsigned.is_empty() {
+        bail!(Error::UnsignedCommits(unsigned.len()));
+    }
+    Ok(())
+}
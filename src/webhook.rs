@@ -0,0 +1,143 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::TcpListener;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::sync::mpsc;
+
+use crate::error::Result;
+use crate::github::Comment;
+
+const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+const EVENT_HEADER: &str = "x-github-event";
+
+/// Serve Github webhook deliveries on the listener and forward newly created
+/// issue comments to `sender`, which decides whether the issue accepts commands
+///
+/// The server stops once `shutdown` completes, leaving the listener to the next
+/// server.
+pub async fn serve(
+    listener: TcpListener,
+    secret: String,
+    sender: mpsc::Sender<Comment>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let secret = Arc::new(secret);
+    let make_svc = make_service_fn(move |_| {
+        let secret = secret.clone();
+        let sender = sender.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, secret.clone(), sender.clone())
+            }))
+        }
+    });
+
+    info!("Webhook receiver listening on {}", listener.local_addr()?);
+    Server::from_tcp(listener)?
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown)
+        .await?;
+
+    Ok(())
+}
+
+async fn handle(
+    req: Request<Body>,
+    secret: Arc<String>,
+    mut sender: mpsc::Sender<Comment>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    let res: Result<StatusCode> = try {
+        if req.method() != Method::POST {
+            return Ok(response(StatusCode::METHOD_NOT_ALLOWED));
+        }
+
+        let signature = header_str(&req, SIGNATURE_HEADER);
+        let event = header_str(&req, EVENT_HEADER);
+        let body = hyper::body::to_bytes(req.into_body()).await?;
+
+        match signature {
+            Some(signature) if verify_signature(secret.as_bytes(), &body, &signature) => (),
+            _ => return Ok(response(StatusCode::UNAUTHORIZED)),
+        }
+
+        match event.as_deref() {
+            Some("issue_comment") => {
+                let event: IssueCommentEvent = serde_json::from_slice(&body)?;
+                if event.action == "created" {
+                    sender.send(event.comment).await?;
+                }
+                StatusCode::OK
+            }
+            // Includes the `ping` event sent when the webhook is created
+            _ => StatusCode::OK,
+        }
+    };
+
+    let status = res.unwrap_or_else(|err| {
+        error!("Webhook delivery failure: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    });
+
+    Ok(response(status))
+}
+
+fn header_str(req: &Request<Body>, name: &str) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|val| val.to_str().ok())
+        .map(ToString::to_string)
+}
+
+fn response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Verify the `X-Hub-Signature-256` header, which is the hex encoded
+/// HMAC-SHA256 digest of the payload prefixed by `sha256=`
+fn verify_signature(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let signature = match signature
+        .strip_prefix("sha256=")
+        .and_then(|hex_digest| hex::decode(hex_digest).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts keys of any size");
+    mac.input(body);
+    mac.verify(&signature).is_ok()
+}
+
+#[derive(Debug, Deserialize)]
+struct IssueCommentEvent {
+    action: String,
+    comment: Comment,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify_signature() {
+        let secret = b"It's a Secret to Everybody";
+        let body = b"Hello, World!";
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+        assert!(verify_signature(secret, body, signature));
+        assert!(!verify_signature(b"wrong secret", body, signature));
+        assert!(!verify_signature(secret, b"Hello, World?", signature));
+        assert!(!verify_signature(secret, body, &signature[7..]));
+        assert!(!verify_signature(secret, body, "sha256=zz"));
+    }
+}
@@ -1,33 +1,65 @@
 use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use elba::package::manifest::{DepReq, Manifest};
+use elba::package::{
+    manifest::{DepReq, Manifest},
+    Name as PackageName,
+};
 use elba::remote::{resolution::DirectRes, RawDep, RawEntry};
+use failure::bail;
 use itertools::Itertools;
 use log::info;
+use semver::Version;
 
-use super::Repo;
+use super::signing::{Signer, Verifier};
 use super::*;
 use crate::config::CONFIG;
 
-use crate::error::Result;
+use crate::error::{Error, Result};
+
+/// The branch proposing the readme in pull request mode, which is shared by
+/// all updates so that only one pull request touches the readme at a time
+pub const README_BRANCH: &str = "update-readme";
 
 pub struct Index {
     repo: Repo,
 }
 
 impl Index {
-    pub fn clone() -> Result<Self> {
+    pub fn clone(signer: Option<Arc<Signer>>) -> Result<Self> {
+        let url = CONFIG
+            .index_repo_url
+            .clone()
+            .unwrap_or_else(|| github_repo_url(&CONFIG.index_repo_name));
         Ok(Index {
             repo: Repo::clone(
-                &github_repo_url(&CONFIG.index_repo_name),
+                &url,
                 &CONFIG.index_checkout,
+                CONFIG.index_branch.as_deref(),
+                CONFIG.index_git_auth,
+                signer,
             )?,
         })
     }
 
-    pub fn update_package(&self, manifest: &Manifest, location: &DirectRes) -> Result<()> {
+    /// The branch packages are published to
+    pub fn branch(&self) -> &str {
+        self.repo.branch()
+    }
+
+    /// Add the entry of the package and render the readme with the package
+    /// list, in a single commit
+    pub fn update_package(
+        &self,
+        manifest: &Manifest,
+        location: &DirectRes,
+        publisher: &database::User,
+        path: Option<&str>,
+        store_commit: Option<&str>,
+        package_list: String,
+    ) -> Result<()> {
         info!(
             "Updating index entries to publish `{} {}`",
             &manifest.package.name, &manifest.package.version
@@ -35,7 +67,100 @@ impl Index {
 
         self.repo.fetch_and_reset()?;
 
-        // Update metafile entry
+        self.commit_publish(
+            manifest,
+            location,
+            publisher,
+            path,
+            store_commit,
+            Some(&package_list),
+        )?;
+        self.repo.push_head()?;
+
+        info!(
+            "Updated index entries to publish `{} {}`",
+            &manifest.package.name, &manifest.package.version
+        );
+
+        Ok(())
+    }
+
+    /// Commit the entry of the package to a new branch and push it, for the
+    /// publish to be merged by a pull request
+    ///
+    /// The readme is left to `propose_readme` once the branch is merged, so
+    /// that pull requests of concurrent publishes don't conflict on it.
+    /// Returns the branch and the diff of it against the index branch.
+    pub fn propose_package(
+        &self,
+        manifest: &Manifest,
+        location: &DirectRes,
+        publisher: &database::User,
+        path: Option<&str>,
+        store_commit: Option<&str>,
+    ) -> Result<(String, String)> {
+        info!(
+            "Proposing index entries to publish `{} {}`",
+            &manifest.package.name, &manifest.package.version
+        );
+
+        self.repo.fetch_and_reset()?;
+
+        let branch = format!(
+            "publish/{}/{}-{}",
+            manifest.package.name.normalized_group(),
+            manifest.package.name.normalized_name(),
+            manifest.package.version
+        );
+        self.repo.checkout_new_branch(&branch)?;
+
+        self.commit_publish(manifest, location, publisher, path, store_commit, None)?;
+        self.repo.push_head()?;
+        let diff = self.repo.diff_from(self.repo.branch())?;
+
+        info!(
+            "Pushed branch `{}` to publish `{} {}`",
+            branch, &manifest.package.name, &manifest.package.version
+        );
+
+        Ok((branch, diff))
+    }
+
+    pub fn delete_branch(&self, branch: &str) -> Result<()> {
+        info!("Deleting index branch `{}`", branch);
+        self.repo.delete_remote_branch(branch)
+    }
+
+    /// Commit the entry of the package, together with the readme rendered with
+    /// the package list if given
+    fn commit_publish(
+        &self,
+        manifest: &Manifest,
+        location: &DirectRes,
+        publisher: &database::User,
+        path: Option<&str>,
+        store_commit: Option<&str>,
+        package_list: Option<&str>,
+    ) -> Result<()> {
+        let mut files = vec![self.write_entry(manifest, location)?];
+        if let Some(package_list) = package_list {
+            files.push(self.write_readme(package_list)?);
+        }
+        self.repo.commit(
+            &publish_commit_msg(
+                "Update Package",
+                &manifest.package.name,
+                &manifest.package.version,
+                publisher,
+                path,
+                store_commit,
+            ),
+            &files,
+        )
+    }
+
+    /// Add the entry of the package to its metafile, returning the path of it
+    fn write_entry(&self, manifest: &Manifest, location: &DirectRes) -> Result<PathBuf> {
         let name = &manifest.package.name;
         let metafile_path = self
             .repo
@@ -49,21 +174,149 @@ impl Index {
         };
         entries.insert(manifest, location)?;
         entries.save(&metafile_path)?;
+        Ok(metafile_path)
+    }
+
+    pub fn remove_package(&self, name: &PackageName, version: &Version) -> Result<()> {
+        info!("Removing index entry of `{} {}`", name, version);
+
+        self.repo.fetch_and_reset()?;
+
+        let metafile_path = self
+            .repo
+            .workdir()?
+            .join(name.normalized_group())
+            .join(name.normalized_name());
+        if !metafile_path.exists() {
+            return Ok(());
+        }
+        let mut entries = Entries::load(&metafile_path)?;
+        entries.remove(name, version);
+        if entries.is_empty() {
+            fs::remove_file(&metafile_path)?;
+        } else {
+            entries.save(&metafile_path)?;
+        }
 
         self.repo.commit_and_push(
-            &format!(
-                "Update Package `{} {}`",
-                &manifest.package.name, &manifest.package.version
-            ),
-            &metafile_path,
+            &format!("Remove Package `{} {}`", name, version),
+            &[&metafile_path],
+        )?;
+
+        info!("Removed index entry of `{} {}`", name, version);
+
+        Ok(())
+    }
+
+    /// Flip the yanked flag in the entry of the package and render the readme
+    /// with the package list, in a single commit
+    ///
+    /// Nothing is committed if the entry is already in the state asked for.
+    pub fn yank_package(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        yanked: bool,
+        package_list: String,
+    ) -> Result<()> {
+        let action = if yanked { "Yank" } else { "Unyank" };
+        info!("{} package `{} {}` in index", action, name, version);
+
+        self.repo.fetch_and_reset()?;
+
+        let metafile_path = match self.write_yanked(name, version, yanked)? {
+            Some(metafile_path) => metafile_path,
+            None => {
+                info!("Package `{} {}` is already in the state", name, version);
+                return Ok(());
+            }
+        };
+        let readme_path = self.write_readme(&package_list)?;
+
+        self.repo.commit_and_push(
+            &format!("{} Package `{} {}`", action, name, version),
+            &[&metafile_path, &readme_path],
+        )?;
+
+        info!("{} package `{} {}` done", action, name, version);
+
+        Ok(())
+    }
+
+    /// Commit the yanked flag flipped in the entry of the package to a new
+    /// branch and force push it, for the yank to be merged by a pull request
+    ///
+    /// The branch is pushed again if the yank is resumed after a restart.
+    /// Returns the branch and the diff of it against the index branch, or
+    /// `None` if the entry is already in the state asked for.
+    pub fn propose_yank(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        yanked: bool,
+    ) -> Result<Option<(String, String)>> {
+        let action = if yanked { "Yank" } else { "Unyank" };
+        let verb = action.to_lowercase();
+        info!("Proposing to {} package `{} {}`", verb, name, version);
+
+        self.repo.fetch_and_reset()?;
+
+        let branch = format!(
+            "{}/{}/{}-{}",
+            verb,
+            name.normalized_group(),
+            name.normalized_name(),
+            version
+        );
+        self.repo.checkout_new_branch(&branch)?;
+
+        let metafile_path = match self.write_yanked(name, version, yanked)? {
+            Some(metafile_path) => metafile_path,
+            None => {
+                info!("Package `{} {}` is already in the state", name, version);
+                return Ok(None);
+            }
+        };
+        self.repo.commit(
+            &format!("{} Package `{} {}`", action, name, version),
+            &[&metafile_path],
         )?;
+        self.repo.force_push_head()?;
+        let diff = self.repo.diff_from(self.repo.branch())?;
 
         info!(
-            "Updated index entries to publish `{} {}`",
-            &manifest.package.name, &manifest.package.version
+            "Pushed branch `{}` to {} package `{} {}`",
+            branch, verb, name, version
         );
 
-        Ok(())
+        Ok(Some((branch, diff)))
+    }
+
+    /// Set the yanked flag in the entry of the package, returning the path of
+    /// the metafile, or `None` if the flag is set that way already
+    fn write_yanked(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        yanked: bool,
+    ) -> Result<Option<PathBuf>> {
+        let metafile_path = self
+            .repo
+            .workdir()?
+            .join(name.normalized_group())
+            .join(name.normalized_name());
+        if !metafile_path.exists() {
+            bail!(Error::PackageNotFound {
+                package: name.to_string(),
+                version: version.clone(),
+            });
+        }
+        let mut entries = Entries::load(&metafile_path)?;
+        if !entries.set_yanked(name, version, yanked)? {
+            return Ok(None);
+        }
+        entries.save(&metafile_path)?;
+        Ok(Some(metafile_path))
     }
 
     pub fn update_readme(&self, package_list: String) -> Result<()> {
@@ -71,27 +324,117 @@ impl Index {
 
         self.repo.fetch_and_reset()?;
 
+        let readme_path = self.write_readme(&package_list)?;
+        self.repo
+            .commit_and_push(&"Update README", &[&readme_path])?;
+
+        info!("Updated index readme");
+
+        Ok(())
+    }
+
+    /// Commit the readme rendered with the package list to `README_BRANCH` and
+    /// force push it, for the readme to be updated by a pull request
+    ///
+    /// The branch starts from the index branch every time, so that it holds the
+    /// latest rendering only. Returns the diff of it against the index branch,
+    /// or `None` if the readme is up to date.
+    pub fn propose_readme(&self, package_list: String) -> Result<Option<String>> {
+        info!("Proposing index readme");
+
+        // Fetches and resets as well
+        if self.is_readme_up_to_date(&package_list)? {
+            info!("Index readme is up to date");
+            return Ok(None);
+        }
+
+        self.repo.checkout_new_branch(README_BRANCH)?;
+        let readme_path = self.write_readme(&package_list)?;
+        self.repo.commit(&"Update README", &[&readme_path])?;
+        self.repo.force_push_head()?;
+        let diff = self.repo.diff_from(self.repo.branch())?;
+
+        info!("Pushed branch `{}` to update readme", README_BRANCH);
+
+        Ok(Some(diff))
+    }
+
+    /// Render the readme with the package list, returning the path of it
+    fn write_readme(&self, package_list: &str) -> Result<PathBuf> {
         let readme_path = self.repo.workdir()?.join("README.md");
-        let readme_template_path = self.repo.workdir()?.join("README.TEMPLATE");
+        let content = self.render_readme(package_list)?;
         let mut readme = OpenOptions::new()
             .truncate(true)
             .write(true)
             .create(true)
             .open(&readme_path)?;
-        let mut readme_template = File::open(readme_template_path)?;
+        readme.write_all(content.as_bytes())?;
+        readme.sync_all()?;
+        Ok(readme_path)
+    }
 
-        // Read the README template and replace the package list placeholder
+    /// Read the README template and replace the package list placeholder
+    fn render_readme(&self, package_list: &str) -> Result<String> {
+        let readme_template_path = self.repo.workdir()?.join("README.TEMPLATE");
+        let mut readme_template = File::open(readme_template_path)?;
         let mut content = String::new();
         readme_template.read_to_string(&mut content)?;
-        content = content.replace("{#package-list#}", &package_list);
-        readme.write_all(content.as_bytes())?;
-        readme.sync_all()?;
+        Ok(content.replace("{#package-list#}", package_list))
+    }
 
-        self.repo.commit_and_push(&"Update README", &readme_path)?;
+    /// Check whether the README in the index is rendered from the package list
+    pub fn is_readme_up_to_date(&self, package_list: &str) -> Result<bool> {
+        self.repo.fetch_and_reset()?;
 
-        info!("Updated index readme");
+        let readme_path = self.repo.workdir()?.join("README.md");
+        if !readme_path.exists() {
+            return Ok(false);
+        }
+        Ok(fs::read_to_string(readme_path)? == self.render_readme(package_list)?)
+    }
 
-        Ok(())
+    /// Messages of all commits in the index, from the oldest to the newest
+    pub fn commit_messages(&self) -> Result<Vec<String>> {
+        self.repo.fetch_and_reset()?;
+        self.repo.commit_messages()
+    }
+
+    /// Commits of the index changing metafiles without the signature of the bot,
+    /// after the commit `since` if given
+    pub fn unsigned_metafile_commits(
+        &self,
+        verifier: &Verifier,
+        since: Option<&str>,
+    ) -> Result<Vec<String>> {
+        self.repo.fetch_and_reset()?;
+
+        // Metafiles are at `group/name`, apart from hidden directories like `.github`
+        self.repo.unsigned_commits(verifier, since, |path| {
+            path.components().count() == 2 && !path.to_string_lossy().starts_with('.')
+        })
+    }
+
+    /// Load the entries of all packages in the index
+    pub fn load_all_entries(&self) -> Result<Vec<RawEntry>> {
+        self.repo.fetch_and_reset()?;
+
+        let mut all_entries = Vec::new();
+        for group_dir in fs::read_dir(self.repo.workdir()?)? {
+            let group_dir = group_dir?;
+            // Skip files like README and hidden directories like `.git`
+            if !group_dir.file_type()?.is_dir()
+                || group_dir.file_name().to_string_lossy().starts_with('.')
+            {
+                continue;
+            }
+            for metafile in fs::read_dir(group_dir.path())? {
+                let metafile = metafile?;
+                if metafile.file_type()?.is_file() {
+                    all_entries.extend(Entries::load(&metafile.path())?.0);
+                }
+            }
+        }
+        Ok(all_entries)
     }
 }
 
@@ -162,4 +505,37 @@ impl Entries {
 
         Ok(())
     }
+
+    pub fn remove(&mut self, name: &PackageName, version: &Version) {
+        self.0
+            .retain(|entry| &entry.name != name || &entry.version != version);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Set the yanked flag of the entry, returning whether it has changed
+    pub fn set_yanked(
+        &mut self,
+        name: &PackageName,
+        version: &Version,
+        yanked: bool,
+    ) -> Result<bool> {
+        let entry = self
+            .0
+            .iter_mut()
+            .find(|entry| &entry.name == name && &entry.version == version);
+        match entry {
+            Some(entry) if entry.yanked == yanked => Ok(false),
+            Some(entry) => {
+                entry.yanked = yanked;
+                Ok(true)
+            }
+            None => bail!(Error::PackageNotFound {
+                package: name.to_string(),
+                version: version.clone(),
+            }),
+        }
+    }
 }
@@ -1,14 +1,20 @@
 mod index;
 mod repo;
+mod signing;
 mod store;
 
-pub use self::index::Index;
+pub use self::index::{Index, README_BRANCH};
 pub use self::repo::Repo;
+pub use self::signing::Verifier;
 pub use self::store::Store;
 
-use elba::package::manifest::Manifest;
+use std::sync::Arc;
 
-use crate::config::CONFIG;
+use elba::package::Name as PackageName;
+use semver::Version;
+
+use self::signing::Signer;
+use crate::database;
 use crate::error::Result;
 
 pub struct Workspace {
@@ -18,33 +24,143 @@ pub struct Workspace {
 
 impl Workspace {
     pub fn new() -> Result<Self> {
+        // Only the repositories of the bot sign commits, not those of packages
+        let signer = Signer::from_config()?.map(Arc::new);
         Ok(Workspace {
-            index: Index::clone()?,
-            store: Store::clone()?,
+            index: Index::clone(signer.clone())?,
+            store: Store::new(signer)?,
         })
     }
 }
 
-fn tarball_name(manifest: &Manifest) -> String {
+fn tarball_name(name: &PackageName, version: &Version) -> String {
     format!(
         "{}_{}_{}.tar.gz",
-        &manifest.package.name.normalized_group(),
-        &manifest.package.name.normalized_name(),
-        &manifest.package.version
+        name.normalized_group(),
+        name.normalized_name(),
+        version
     )
 }
 
-fn github_raw_url(head_hash: &str, manifest: &Manifest) -> String {
+fn github_raw_url(
+    repo_name: &str,
+    head_hash: &str,
+    name: &PackageName,
+    version: &Version,
+) -> String {
     format!(
         "https://github.com/{}/blob/{}/{}/{}/{}?raw=true",
-        &CONFIG.store_repo_name,
+        repo_name,
         head_hash,
-        &manifest.package.name.normalized_group(),
-        &manifest.package.name.normalized_name(),
-        &tarball_name(manifest)
+        name.normalized_group(),
+        name.normalized_name(),
+        &tarball_name(name, version)
     )
 }
 
 fn github_repo_url(repo_name: &str) -> String {
     format!("https://github.com/{}.git", repo_name)
 }
+
+/// Message of the commit publishing a package, which records the publisher and
+/// the directory of the package in its repository so that the database can be
+/// rebuilt from history, and the store commit holding the tarball if the store
+/// is a repository
+fn publish_commit_msg(
+    action: &str,
+    name: &PackageName,
+    version: &Version,
+    publisher: &database::User,
+    path: Option<&str>,
+    store_commit: Option<&str>,
+) -> String {
+    let mut msg = format!(
+        "{} `{} {}`\n\nPublished-by: @{} ({})\n",
+        action, name, version, publisher.name, publisher.id
+    );
+    if let Some(path) = path {
+        msg += &format!("Package-path: {}\n", path);
+    }
+    if let Some(store_commit) = store_commit {
+        msg += &format!("Store-commit: {}\n", store_commit);
+    }
+    msg
+}
+
+/// Parse the package, the publisher and the directory of the package from a
+/// message made by `publish_commit_msg`
+pub fn parse_publish_commit_msg(
+    msg: &str,
+) -> Option<(PackageName, Version, database::User, Option<String>)> {
+    let subject = msg.lines().next()?;
+    let mut package = subject.split('`').nth(1)?.split(' ');
+    let name = package.next()?.parse().ok()?;
+    let version = Version::parse(package.next()?).ok()?;
+
+    let trailer = msg
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Published-by: @"))?;
+    let mut trailer = trailer.splitn(2, " (");
+    let user_name = trailer.next()?.to_owned();
+    let user_id = trailer.next()?.strip_suffix(")")?.parse().ok()?;
+    let path = msg
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Package-path: "))
+        .map(ToOwned::to_owned);
+
+    Some((
+        name,
+        version,
+        database::User {
+            id: user_id,
+            name: user_name,
+        },
+        path,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_publish_commit_msg() {
+        let publisher = database::User {
+            id: 42,
+            name: "someone".to_owned(),
+        };
+        let name: PackageName = "group/pkg".parse().unwrap();
+        let version = Version::parse("1.2.3").unwrap();
+        let msg = publish_commit_msg(
+            "Update Package",
+            &name,
+            &version,
+            &publisher,
+            None,
+            Some("0123456789abcdef"),
+        );
+        assert!(msg.contains("Store-commit: 0123456789abcdef"));
+
+        let (parsed_name, parsed_version, parsed_publisher, parsed_path) =
+            parse_publish_commit_msg(&msg).unwrap();
+        assert_eq!(parsed_name, name);
+        assert_eq!(parsed_version, version);
+        assert_eq!(parsed_publisher.id, 42);
+        assert_eq!(parsed_publisher.name, "someone");
+        assert_eq!(parsed_path, None);
+
+        let msg = publish_commit_msg(
+            "Update Package",
+            &name,
+            &version,
+            &publisher,
+            Some("packages/pkg"),
+            None,
+        );
+        let (_, _, _, parsed_path) = parse_publish_commit_msg(&msg).unwrap();
+        assert_eq!(parsed_path.as_deref(), Some("packages/pkg"));
+
+        assert!(parse_publish_commit_msg("Update README").is_none());
+        assert!(parse_publish_commit_msg("Update Package `group/pkg 1.2.3`").is_none());
+    }
+}
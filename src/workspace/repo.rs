@@ -1,21 +1,57 @@
 use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use failure::bail;
-use git2::{build::CheckoutBuilder, Cred, PushOptions, Repository};
-use log::info;
+use git2::{
+    build::{CheckoutBuilder, RepoBuilder},
+    Commit, Cred, DiffFormat, Direction, ErrorCode, FetchOptions, Oid, PushOptions,
+    RemoteCallbacks, Repository, Signature, Sort, Tree,
+};
+use itertools::Itertools;
+use log::{info, warn};
 
-use crate::config::CONFIG;
+use crate::config::{GitAuth, CONFIG};
 use crate::error::{Error, Result};
+use crate::github::auth;
+use crate::workspace::signing::{Signer, Verifier};
+
+/// How many times to push before giving up on concurrent pushes
+const PUSH_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry of a push, which doubles on every retry
+const PUSH_RETRY_DELAY: Duration = Duration::from_millis(500);
 
 pub struct Repo {
     repo: Repository,
+    /// The branch to fetch and push
+    branch: String,
+    /// Signs the commits if commit signing is enabled
+    signer: Option<Arc<Signer>>,
+    /// How to authenticate to origin
+    auth: GitAuth,
 }
 
 impl Repo {
-    pub fn clone(url: &str, checkout: &Path) -> Result<Self> {
+    /// Clone the repository, or open it if it's checked out already
+    ///
+    /// The branch defaults to the default branch of the remote. Commits are
+    /// signed by the signer if any.
+    pub fn clone(
+        url: &str,
+        checkout: &Path,
+        branch: Option<&str>,
+        auth: GitAuth,
+        signer: Option<Arc<Signer>>,
+    ) -> Result<Self> {
         let repo = Repository::open(checkout).or_else(|_| {
             info!("Cloning repo {} to {:?}", url, checkout);
-            let repo = Repository::clone(url, checkout);
+            let mut fetch_options = FetchOptions::new();
+            fetch_options.remote_callbacks(remote_callbacks(auth));
+            let repo = RepoBuilder::new()
+                .fetch_options(fetch_options)
+                .clone(url, checkout);
             info!("Cloned repo {} to {:?}", url, checkout);
             repo
         })?;
@@ -23,9 +59,24 @@ impl Repo {
         // git config
         let mut repo_cfg = repo.config()?;
         repo_cfg.set_str("user.name", &CONFIG.bot_name)?;
-        repo_cfg.set_str("user.email", &CONFIG.bot_email)?;
+        repo_cfg.set_str("user.email", &CONFIG.commit_email())?;
+
+        let branch = match branch {
+            Some(branch) => branch.to_owned(),
+            None => default_branch(&repo, auth)?,
+        };
+        info!("Using branch `{}` of repo {}", branch, url);
 
-        Ok(Repo { repo })
+        Ok(Repo {
+            repo,
+            branch,
+            signer,
+            auth,
+        })
+    }
+
+    pub fn branch(&self) -> &str {
+        &self.branch
     }
 
     pub fn workdir(&self) -> Result<&Path> {
@@ -49,20 +100,54 @@ impl Repo {
     pub fn fetch_and_reset(&self) -> Result<()> {
         // git pull origin
         let mut remote = self.repo.find_remote("origin")?;
-        remote.fetch(&["refs/heads/master:refs/heads/master"], None, None)?;
+        let refname = format!("refs/heads/{}", self.branch);
+        remote.fetch(
+            &[&format!("{0}:{0}", refname)],
+            Some(&mut self.fetch_options()),
+            None,
+        )?;
 
         // git checkout HEAD -f
-        self.repo.set_head("refs/heads/master")?;
+        self.repo.set_head(&refname)?;
         self.repo
             .checkout_head(Some(CheckoutBuilder::new().force()))?;
 
         Ok(())
     }
 
-    pub fn commit_and_push(&self, msg: &str, file: &Path) -> Result<()> {
-        // git add
+    /// Messages of the commits reachable from HEAD, from the oldest to the newest
+    pub fn commit_messages(&self) -> Result<Vec<String>> {
+        // git log --reverse
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+
+        let mut messages = Vec::new();
+        for oid in revwalk {
+            let commit = self.repo.find_commit(oid?)?;
+            messages.push(commit.message().unwrap_or_default().to_owned());
+        }
+        Ok(messages)
+    }
+
+    pub fn commit_and_push<P: AsRef<Path>>(&self, msg: &str, files: &[P]) -> Result<()> {
+        self.commit(msg, files)?;
+        self.push_head()
+    }
+
+    /// Commit the changes of the files at once
+    pub fn commit<P: AsRef<Path>>(&self, msg: &str, files: &[P]) -> Result<()> {
+        // git add, or git rm if the file has been deleted
         let mut index = self.repo.index()?;
-        index.add_path(&file.strip_prefix(self.repo.workdir().unwrap())?)?;
+        for file in files {
+            let file = file.as_ref();
+            let path = file.strip_prefix(self.workdir()?)?;
+            if file.exists() {
+                index.add_path(path)?;
+            } else {
+                index.remove_path(path)?;
+            }
+        }
         index.write()?;
         let tree_id = index.write_tree()?;
         let tree = self.repo.find_tree(tree_id)?;
@@ -73,28 +158,433 @@ impl Repo {
         let sig = self.repo.signature()?;
 
         // git commit -m
+        let commit = self.create_commit(&sig, &sig, msg, &tree, &[&parent])?;
+        let refname = head.name().ok_or(Error::NoInitialCommit)?;
+        self.repo.reference(refname, commit, true, msg)?;
+
+        Ok(())
+    }
+
+    /// Create a commit without updating any ref, signed if signing is enabled
+    fn create_commit(
+        &self,
+        author: &Signature,
+        committer: &Signature,
+        msg: &str,
+        tree: &Tree,
+        parents: &[&Commit],
+    ) -> Result<Oid> {
+        match &self.signer {
+            // git commit -S
+            Some(signer) => {
+                let content = self
+                    .repo
+                    .commit_create_buffer(author, committer, msg, tree, parents)?;
+                let content = String::from_utf8(content.to_vec())?;
+                let signature = signer.sign(&content)?;
+                Ok(self.repo.commit_signed(&content, &signature, None)?)
+            }
+            None => Ok(self
+                .repo
+                .commit(None, author, committer, msg, tree, parents)?),
+        }
+    }
+
+    /// Commits reachable from HEAD that change a path accepted by the filter,
+    /// but are not signed by the key of the verifier, as `hash summary`
+    ///
+    /// Only commits after `since` are checked if it's given. Merge commits are
+    /// skipped, since the commits they merge are checked on their own.
+    pub fn unsigned_commits(
+        &self,
+        verifier: &Verifier,
+        since: Option<&str>,
+        filter: impl Fn(&Path) -> bool,
+    ) -> Result<Vec<String>> {
+        // git log --reverse since..HEAD
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push_head()?;
+        if let Some(since) = since {
+            revwalk.hide(self.repo.revparse_single(since)?.peel_to_commit()?.id())?;
+        }
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+
+        let mut unsigned = Vec::new();
+        for oid in revwalk {
+            let oid = oid?;
+            let commit = self.repo.find_commit(oid)?;
+            if commit.parent_count() > 1 {
+                continue;
+            }
+
+            // git show --name-only
+            let parent_tree = match commit.parent(0) {
+                Ok(parent) => Some(parent.tree()?),
+                Err(_) => None,
+            };
+            let diff =
+                self.repo
+                    .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+            let touched = diff.deltas().any(|delta| {
+                delta
+                    .new_file()
+                    .path()
+                    .or_else(|| delta.old_file().path())
+                    .map_or(false, &filter)
+            });
+            if !touched {
+                continue;
+            }
+
+            // git verify-commit
+            let signed = match self.repo.extract_signature(&oid, None) {
+                Ok((signature, content)) => verifier.verify(
+                    content.as_str().unwrap_or_default(),
+                    signature.as_str().unwrap_or_default(),
+                )?,
+                Err(error) if error.code() == ErrorCode::NotFound => false,
+                Err(error) => return Err(error.into()),
+            };
+            if !signed {
+                unsigned.push(format!("{} {}", oid, commit.summary().unwrap_or_default()));
+            }
+        }
+        Ok(unsigned)
+    }
+
+    /// Push the branch checked out to the branch of the same name in origin
+    ///
+    /// If someone else has pushed in the meantime, the local commits are
+    /// replayed on top of theirs and pushed again, for a few times with growing
+    /// delays. Fails with `Error::GitConflict` if they changed the same files.
+    pub fn push_head(&self) -> Result<()> {
+        let refname = self
+            .repo
+            .head()?
+            .name()
+            .ok_or(Error::NoInitialCommit)?
+            .to_owned();
+
+        let mut attempt = 1;
+        let mut delay = PUSH_RETRY_DELAY;
+        loop {
+            // git push origin HEAD
+            match self.push(&refname, &refname) {
+                Err(error) if attempt < PUSH_ATTEMPTS && is_push_rejected(&error) => {
+                    warn!(
+                        "Push to {} rejected, replaying on remote and retrying in {:?}: {}",
+                        refname, delay, error
+                    );
+                    thread::sleep(delay);
+                    self.replay_on_remote()?;
+                    attempt += 1;
+                    delay *= 2;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Push the branch checked out to the branch of the same name in origin,
+    /// replacing whatever it holds there
+    pub fn force_push_head(&self) -> Result<()> {
+        let refname = self
+            .repo
+            .head()?
+            .name()
+            .ok_or(Error::NoInitialCommit)?
+            .to_owned();
+
+        // git push -f origin HEAD
+        self.push(&format!("+{}", refname), &refname)
+    }
+
+    /// Replay the commits that are only in HEAD on top of the same branch in
+    /// origin
+    fn replay_on_remote(&self) -> Result<()> {
+        let head = self.repo.head()?;
+        let refname = head.name().ok_or(Error::NoInitialCommit)?.to_owned();
+        let branch = refname.trim_start_matches("refs/heads/");
+
+        // git fetch origin
+        let remote_refname = format!("refs/remotes/origin/{}", branch);
+        let mut remote = self.repo.find_remote("origin")?;
+        remote.fetch(
+            &[&format!("+{}:{}", refname, remote_refname)],
+            Some(&mut self.fetch_options()),
+            None,
+        )?;
+        let remote_head = self.repo.refname_to_id(&remote_refname)?;
+
+        // git log origin/branch..HEAD
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push_head()?;
+        revwalk.hide(remote_head)?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        let local_commits = revwalk.collect::<std::result::Result<Vec<_>, _>>()?;
+
+        // git cherry-pick
+        let mut onto = self.repo.find_commit(remote_head)?;
+        for oid in local_commits {
+            let commit = self.repo.find_commit(oid)?;
+            let mut index = self.repo.cherrypick_commit(&commit, &onto, 0, None)?;
+            if index.has_conflicts() {
+                let files = index
+                    .conflicts()?
+                    .filter_map(|conflict| {
+                        let conflict = conflict.ok()?;
+                        let entry = conflict.our.or(conflict.their)?;
+                        Some(String::from_utf8_lossy(&entry.path).into_owned())
+                    })
+                    .join(", ");
+                bail!(Error::GitConflict(files));
+            }
+            let tree = self.repo.find_tree(index.write_tree_to(&self.repo)?)?;
+            let replayed = self.create_commit(
+                &commit.author(),
+                &commit.committer(),
+                commit.message().unwrap_or_default(),
+                &tree,
+                &[&onto],
+            )?;
+            onto = self.repo.find_commit(replayed)?;
+        }
+
+        // git reset --hard
+        self.repo
+            .reference(&refname, onto.id(), true, "replay on remote")?;
         self.repo
-            .commit(Some("HEAD"), &sig, &sig, msg, &tree, &[&parent])?;
+            .checkout_head(Some(CheckoutBuilder::new().force()))?;
+
+        Ok(())
+    }
+
+    /// Create a branch at HEAD and switch to it, so that following commits are
+    /// pushed to the branch
+    pub fn checkout_new_branch(&self, branch: &str) -> Result<()> {
+        // git checkout -b
+        let head = self.repo.head()?.peel_to_commit()?;
+        self.repo.branch(branch, &head, true)?;
+        self.repo.set_head(&format!("refs/heads/{}", branch))?;
+        Ok(())
+    }
+
+    pub fn delete_remote_branch(&self, branch: &str) -> Result<()> {
+        // git push origin :branch
+        let refname = format!("refs/heads/{}", branch);
+        self.push(&format!(":{}", refname), &refname)
+    }
+
+    /// Patch of HEAD against the local branch `base`
+    pub fn diff_from(&self, base: &str) -> Result<String> {
+        // git diff base HEAD
+        let base_tree = self
+            .repo
+            .revparse_single(&format!("refs/heads/{}", base))?
+            .peel_to_tree()?;
+        let head_tree = self.repo.head()?.peel_to_tree()?;
+        let diff = self
+            .repo
+            .diff_tree_to_tree(Some(&base_tree), Some(&head_tree), None)?;
+
+        let mut patch = String::new();
+        diff.print(DiffFormat::Patch, |_, _, line| {
+            if let '+' | '-' | ' ' = line.origin() {
+                patch.push(line.origin());
+            }
+            patch.push_str(&String::from_utf8_lossy(line.content()));
+            true
+        })?;
+        Ok(patch)
+    }
 
-        // git push
+    fn push(&self, refspec: &str, refname: &str) -> Result<()> {
         let mut remote = self.repo.find_remote("origin")?;
         let mut push_err_msg = None;
-        let mut callbacks = git2::RemoteCallbacks::new();
-        callbacks
-            .credentials(|_, _, _| Cred::userpass_plaintext(&CONFIG.bot_email, &CONFIG.bot_pwd));
-        callbacks.push_update_reference(|refname, status| {
-            assert_eq!(refname, "refs/heads/master");
-            push_err_msg = status.map(|s| s.to_string());
+        let mut callbacks = remote_callbacks(self.auth);
+        callbacks.push_update_reference(|pushed_refname, status| {
+            push_err_msg = if pushed_refname != refname {
+                Some(format!(
+                    "remote updated `{}` instead of `{}`",
+                    pushed_refname, refname
+                ))
+            } else {
+                status.map(|s| s.to_string())
+            };
             Ok(())
         });
-        remote.push(
-            &["refs/heads/master"],
+        let res = remote.push(
+            &[refspec],
             Some(PushOptions::new().remote_callbacks(callbacks)),
-        )?;
+        );
+        match res {
+            Err(error) if error.code() == ErrorCode::NotFastForward => {
+                bail!(Error::GitPushRejected(error.message().to_owned()))
+            }
+            res => res?,
+        }
         if let Some(push_err_msg) = push_err_msg {
+            // Github rejects with `fetch first` or `non-fast-forward`
+            if push_err_msg.contains("fetch first") || push_err_msg.contains("fast-forward") {
+                bail!(Error::GitPushRejected(push_err_msg));
+            }
             bail!(Error::GitPush(push_err_msg));
         }
 
         Ok(())
     }
+
+    fn fetch_options(&self) -> FetchOptions<'static> {
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(remote_callbacks(self.auth));
+        fetch_options
+    }
+}
+
+/// Callbacks authenticating to remotes by the configured method
+fn remote_callbacks<'a>(auth: GitAuth) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |_, user_name_from_url, _| {
+        credentials(auth, user_name_from_url)
+            .map_err(|error| git2::Error::from_str(&error.to_string()))
+    });
+    callbacks
+}
+
+fn credentials(auth: GitAuth, user_name_from_url: Option<&str>) -> Result<Cred> {
+    // Token and SSH remotes name their user in the url, like `git@github.com`
+    let user_name = user_name_from_url.unwrap_or(match auth {
+        GitAuth::Token => "x-access-token",
+        _ => "git",
+    });
+    match auth {
+        GitAuth::Github => {
+            let (user_name, password) = auth::git_credentials()?;
+            Ok(Cred::userpass_plaintext(&user_name, &password)?)
+        }
+        GitAuth::Token => {
+            let token = CONFIG
+                .git_token
+                .as_ref()
+                .ok_or(Error::MissingConfig("GIT_TOKEN"))?;
+            Ok(Cred::userpass_plaintext(user_name, token)?)
+        }
+        GitAuth::SshKey => {
+            let key = CONFIG
+                .git_ssh_key
+                .as_ref()
+                .ok_or(Error::MissingConfig("GIT_SSH_KEY"))?;
+            Ok(Cred::ssh_key(
+                user_name,
+                None,
+                key,
+                CONFIG.git_ssh_key_passphrase.as_deref(),
+            )?)
+        }
+        GitAuth::SshAgent => Ok(Cred::ssh_key_from_agent(user_name)?),
+        GitAuth::Anonymous => bail!(Error::NoGitCredentials),
+    }
+}
+
+/// Whether the push failed because the remote has commits unknown locally
+fn is_push_rejected(error: &failure::Error) -> bool {
+    match error.downcast_ref::<Error>() {
+        Some(Error::GitPushRejected(_)) => true,
+        _ => false,
+    }
+}
+
+/// The branch HEAD of origin points to, which a fresh clone records as
+/// `refs/remotes/origin/HEAD`, or otherwise is asked from the remote
+fn default_branch(repo: &Repository, auth: GitAuth) -> Result<String> {
+    if let Ok(origin_head) = repo.find_reference("refs/remotes/origin/HEAD") {
+        if let Some(target) = origin_head.symbolic_target() {
+            if let Some(branch) = target.strip_prefix("refs/remotes/origin/") {
+                return Ok(branch.to_owned());
+            }
+        }
+    }
+
+    // git ls-remote --symref origin HEAD
+    let mut remote = repo.find_remote("origin")?;
+    let url = remote.url().unwrap_or_default().to_owned();
+    let connection = remote.connect_auth(Direction::Fetch, Some(remote_callbacks(auth)), None)?;
+    let default = connection.default_branch()?;
+    match default
+        .as_str()
+        .unwrap_or_default()
+        .strip_prefix("refs/heads/")
+    {
+        Some(branch) => Ok(branch.to_owned()),
+        None => bail!(Error::NoDefaultBranch(url)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    /// Clone the repository without touching `CONFIG`
+    fn clone(url: &str, path: &Path) -> Repo {
+        let repo = Repository::clone(url, path).unwrap();
+        let mut repo_cfg = repo.config().unwrap();
+        repo_cfg.set_str("user.name", "test").unwrap();
+        repo_cfg.set_str("user.email", "test@example.com").unwrap();
+        Repo {
+            repo,
+            branch: "master".to_owned(),
+            signer: None,
+            auth: GitAuth::Anonymous,
+        }
+    }
+
+    fn commit_file(repo: &Repo, name: &str, content: &str) -> Result<()> {
+        let path = repo.workdir().unwrap().join(name);
+        fs::write(&path, content).unwrap();
+        repo.commit_and_push(&format!("Update {}", name), &[&path])
+    }
+
+    #[test]
+    fn test_push_concurrent_changes() {
+        let root = TempDir::new("elba-bot-repo").unwrap();
+        let origin = Repository::init_bare(root.path().join("origin.git")).unwrap();
+        // The clones work on master, whatever `init.defaultBranch` is
+        origin.set_head("refs/heads/master").unwrap();
+        let tree = origin
+            .find_tree(origin.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        origin
+            .commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[])
+            .unwrap();
+        let url = format!("file://{}", root.path().join("origin.git").display());
+
+        let ours = clone(&url, &root.path().join("ours"));
+        let theirs = clone(&url, &root.path().join("theirs"));
+
+        // Changes of different files are both kept
+        commit_file(&theirs, "a", "theirs").unwrap();
+        commit_file(&ours, "b", "ours").unwrap();
+        theirs.fetch_and_reset().unwrap();
+        assert_eq!(
+            theirs.commit_messages().unwrap(),
+            ["Initial commit", "Update a", "Update b"]
+        );
+        assert_eq!(
+            fs::read_to_string(theirs.workdir().unwrap().join("b")).unwrap(),
+            "ours"
+        );
+
+        // Changes of the same file conflict
+        commit_file(&theirs, "b", "theirs").unwrap();
+        let error = commit_file(&ours, "b", "ours again").unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::GitConflict(files)) => assert_eq!(files, "b"),
+            _ => panic!("expected a conflict but got {}", error),
+        }
+    }
 }
@@ -0,0 +1,286 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use failure::bail;
+use log::info;
+use tempdir::TempDir;
+
+use crate::config::{SigningFormat, CONFIG};
+use crate::error::{Error, Result};
+
+/// Signs commits with the key of the bot, by the same programs git uses
+pub struct Signer {
+    format: SigningFormat,
+    key: PathBuf,
+    /// The keyring the OpenPGP key is imported into, leaving the keyring of the
+    /// host untouched
+    gnupg_home: Option<TempDir>,
+}
+
+impl Signer {
+    /// The signer configured by `COMMIT_SIGNING`, or `None` if commits are not
+    /// signed
+    pub fn from_config() -> Result<Option<Self>> {
+        let format = match CONFIG.commit_signing {
+            Some(format) => format,
+            None => return Ok(None),
+        };
+        let key = CONFIG
+            .commit_signing_key
+            .clone()
+            .ok_or(Error::MissingConfig("COMMIT_SIGNING_KEY"))?;
+        Ok(Some(Signer::new(format, key)?))
+    }
+
+    fn new(format: SigningFormat, key: PathBuf) -> Result<Self> {
+        let gnupg_home = match format {
+            SigningFormat::Openpgp => {
+                let home = TempDir::new("elba-bot-gnupg")?;
+                info!("Importing OpenPGP key {:?}", key);
+                run(
+                    Command::new("gpg")
+                        .arg("--homedir")
+                        .arg(home.path())
+                        .args(&["--batch", "--import"])
+                        .arg(&key),
+                    &[],
+                )?;
+                Some(home)
+            }
+            SigningFormat::Ssh => None,
+        };
+        Ok(Signer {
+            format,
+            key,
+            gnupg_home,
+        })
+    }
+
+    /// Make a detached signature of the commit content
+    pub fn sign(&self, content: &str) -> Result<String> {
+        match self.format {
+            SigningFormat::Openpgp => run(
+                Command::new("gpg")
+                    .arg("--homedir")
+                    .arg(self.gnupg_home())
+                    .args(&["--batch", "--armor", "--detach-sign"]),
+                content.as_bytes(),
+            ),
+            SigningFormat::Ssh => run(
+                Command::new("ssh-keygen")
+                    .args(&["-Y", "sign", "-n", "git", "-f"])
+                    .arg(&self.key),
+                content.as_bytes(),
+            ),
+        }
+    }
+
+    fn gnupg_home(&self) -> &Path {
+        self.gnupg_home
+            .as_ref()
+            .expect("OpenPGP keys are imported on creation")
+            .path()
+    }
+}
+
+/// The identity the SSH key is allowed to sign as, which is checked nowhere
+/// else
+const SSH_PRINCIPAL: &str = "elba-bot";
+
+/// Verifies signatures against the public key of the bot, so that anyone can
+/// check the commits without the secret key
+pub struct Verifier {
+    format: SigningFormat,
+    /// The keyring the OpenPGP certificate is imported into, or the directory
+    /// of the allowed signers file holding the SSH public key
+    dir: TempDir,
+}
+
+impl Verifier {
+    /// Load an SSH public key, or an OpenPGP certificate, armored or not
+    pub fn new(public_key: &Path) -> Result<Self> {
+        let content = fs::read(public_key)?;
+        let dir = TempDir::new("elba-bot-verify")?;
+        let is_ssh = ["ssh-", "ecdsa-", "sk-"]
+            .iter()
+            .any(|prefix| content.starts_with(prefix.as_bytes()));
+        let format = if is_ssh {
+            fs::write(
+                dir.path().join("allowed_signers"),
+                format!(
+                    "{} {}",
+                    SSH_PRINCIPAL,
+                    String::from_utf8_lossy(&content).trim()
+                ),
+            )?;
+            SigningFormat::Ssh
+        } else {
+            info!("Importing OpenPGP certificate {:?}", public_key);
+            run(
+                Command::new("gpg")
+                    .arg("--homedir")
+                    .arg(dir.path())
+                    .args(&["--batch", "--import"])
+                    .arg(public_key),
+                &[],
+            )?;
+            SigningFormat::Openpgp
+        };
+        Ok(Verifier { format, dir })
+    }
+
+    /// Check that the signature of the commit content is made by the key
+    pub fn verify(&self, content: &str, signature: &str) -> Result<bool> {
+        let signature_dir = TempDir::new("elba-bot-signature")?;
+        let signature_path = signature_dir.path().join("signature");
+        fs::write(&signature_path, signature)?;
+
+        let output = match self.format {
+            // The keyring holds nothing but the certificate
+            SigningFormat::Openpgp => pipe(
+                Command::new("gpg")
+                    .arg("--homedir")
+                    .arg(self.dir.path())
+                    .args(&["--batch", "--verify"])
+                    .arg(&signature_path)
+                    .arg("-"),
+                content.as_bytes(),
+            )?,
+            SigningFormat::Ssh => pipe(
+                Command::new("ssh-keygen")
+                    .args(&["-Y", "verify", "-n", "git", "-f"])
+                    .arg(self.dir.path().join("allowed_signers"))
+                    .arg("-I")
+                    .arg(SSH_PRINCIPAL)
+                    .arg("-s")
+                    .arg(&signature_path),
+                content.as_bytes(),
+            )?,
+        };
+        Ok(output.status.success())
+    }
+}
+
+/// Run the program with the input, returning its output
+fn run(command: &mut Command, input: &[u8]) -> Result<String> {
+    let output = pipe(command, input)?;
+    if !output.status.success() {
+        bail!(Error::CommitSigning(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+fn pipe(command: &mut Command, input: &[u8]) -> Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(input)?;
+    Ok(child.wait_with_output()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Whether the program can be run, as its tests are skipped otherwise
+    fn installed(program: &str) -> bool {
+        Command::new(program)
+            .arg("--help")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok()
+    }
+
+    /// Generate an SSH key without passphrase, returning the paths of the
+    /// private key and the public key
+    fn ssh_key(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let key = dir.join(name);
+        run(
+            Command::new("ssh-keygen")
+                .args(&["-q", "-t", "ed25519", "-N", "", "-C", name, "-f"])
+                .arg(&key),
+            &[],
+        )
+        .unwrap();
+        (key.clone(), key.with_extension("pub"))
+    }
+
+    /// Generate an OpenPGP key without passphrase, returning the paths of the
+    /// armored secret key and certificate
+    fn openpgp_key(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let home = TempDir::new("elba-bot-gnupg").unwrap();
+        let gpg = |args: &[&str]| {
+            run(
+                Command::new("gpg")
+                    .arg("--homedir")
+                    .arg(home.path())
+                    .args(&["--batch", "--pinentry-mode", "loopback", "--passphrase", ""])
+                    .args(args),
+                &[],
+            )
+            .unwrap()
+        };
+        gpg(&[
+            "--quick-generate-key",
+            &format!("{} <{}@example.com>", name, name),
+            "ed25519",
+            "sign",
+            "never",
+        ]);
+        let secret_key = dir.join(format!("{}.key", name));
+        fs::write(&secret_key, gpg(&["--armor", "--export-secret-keys"])).unwrap();
+        let certificate = dir.join(format!("{}.asc", name));
+        fs::write(&certificate, gpg(&["--armor", "--export"])).unwrap();
+        (secret_key, certificate)
+    }
+
+    /// Check the signatures of the signer against its own key and another one
+    fn assert_round_trip(signer: &Signer, verifier: &Verifier, other: &Verifier) {
+        let content = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\nUpdate package\n";
+        let signature = signer.sign(content).unwrap();
+        assert!(verifier.verify(content, &signature).unwrap());
+        assert!(!verifier.verify("Tampered", &signature).unwrap());
+        assert!(!other.verify(content, &signature).unwrap());
+    }
+
+    #[test]
+    fn test_ssh_round_trip() {
+        if !installed("ssh-keygen") {
+            return;
+        }
+        let dir = TempDir::new("elba-bot-signing").unwrap();
+        let (key, public_key) = ssh_key(dir.path(), "bot");
+        let (_, other_public_key) = ssh_key(dir.path(), "other");
+
+        let signer = Signer::new(SigningFormat::Ssh, key).unwrap();
+        assert_round_trip(
+            &signer,
+            &Verifier::new(&public_key).unwrap(),
+            &Verifier::new(&other_public_key).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_openpgp_round_trip() {
+        if !installed("gpg") {
+            return;
+        }
+        let dir = TempDir::new("elba-bot-signing").unwrap();
+        let (key, certificate) = openpgp_key(dir.path(), "bot");
+        let (_, other_certificate) = openpgp_key(dir.path(), "other");
+
+        let signer = Signer::new(SigningFormat::Openpgp, key).unwrap();
+        assert_round_trip(
+            &signer,
+            &Verifier::new(&certificate).unwrap(),
+            &Verifier::new(&other_certificate).unwrap(),
+        );
+    }
+}
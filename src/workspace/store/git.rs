@@ -0,0 +1,140 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use elba::package::{manifest::Manifest, Name as PackageName};
+use itertools::Itertools;
+use log::info;
+use reqwest::Url;
+use semver::Version;
+
+use super::*;
+use crate::config::CONFIG;
+use crate::database;
+use crate::error::{Error, Result};
+use crate::workspace::signing::Signer;
+use crate::workspace::Repo;
+
+/// Store tarballs by committing them into a Github repository
+///
+/// Tarballs are downloaded from the raw file links of Github, unless
+/// `STORE_URL_PREFIX` points to another host serving the repository.
+pub struct GitStore {
+    repo: Repo,
+    repo_name: String,
+    url_prefix: Option<String>,
+}
+
+impl GitStore {
+    pub fn clone(signer: Option<Arc<Signer>>) -> Result<Self> {
+        let repo_name = CONFIG
+            .store_repo_name
+            .clone()
+            .ok_or(Error::MissingConfig("STORE_REPO_NAME"))?;
+        let checkout = CONFIG
+            .store_checkout
+            .as_ref()
+            .ok_or(Error::MissingConfig("STORE_CHECKOUT"))?;
+        let url = CONFIG
+            .store_repo_url
+            .clone()
+            .unwrap_or_else(|| github_repo_url(&repo_name));
+        Ok(GitStore {
+            repo: Repo::clone(
+                &url,
+                checkout,
+                CONFIG.store_branch.as_deref(),
+                CONFIG.store_git_auth,
+                signer,
+            )?,
+            repo_name,
+            url_prefix: CONFIG.store_url_prefix.clone(),
+        })
+    }
+}
+
+impl StorageBackend for GitStore {
+    fn put(&self, manifest: &Manifest, tarball: &Path, publisher: &database::User) -> Result<Url> {
+        self.repo.fetch_and_reset()?;
+
+        // Copy tarball into local repo
+        let tarball_path = self.repo.workdir()?.join(tarball_path(
+            &manifest.package.name,
+            &manifest.package.version,
+        ));
+        fs::create_dir_all(tarball_path.parent().unwrap())?;
+        fs::copy(tarball, &tarball_path)?;
+
+        // Push update to remote
+        self.repo.commit_and_push(
+            &publish_commit_msg(
+                "Update package",
+                &manifest.package.name,
+                &manifest.package.version,
+                publisher,
+                None,
+                None,
+            ),
+            &[&tarball_path],
+        )?;
+        info!(
+            "Pushed package `{} {}` to store repository",
+            &manifest.package.name, &manifest.package.version
+        );
+
+        match &self.url_prefix {
+            Some(url_prefix) => Ok(format!(
+                "{}/{}",
+                url_prefix.trim_end_matches('/'),
+                tarball_path(&manifest.package.name, &manifest.package.version)
+                    .iter()
+                    .map(|part| part.to_string_lossy())
+                    .join("/")
+            )
+            .parse()?),
+            None => Ok(github_raw_url(
+                &self.repo_name,
+                &self.repo.head_hash(),
+                &manifest.package.name,
+                &manifest.package.version,
+            )
+            .parse()?),
+        }
+    }
+
+    fn delete(&self, name: &PackageName, version: &Version) -> Result<()> {
+        self.repo.fetch_and_reset()?;
+
+        let tarball_path = self.repo.workdir()?.join(tarball_path(name, version));
+        if !tarball_path.exists() {
+            return Ok(());
+        }
+        fs::remove_file(&tarball_path)?;
+
+        self.repo.commit_and_push(
+            &format!("Remove package `{} {}`", name, version),
+            &[&tarball_path],
+        )
+    }
+
+    fn refresh(&self) -> Result<()> {
+        self.repo.fetch_and_reset()
+    }
+
+    fn commit_hash(&self) -> Option<String> {
+        Some(self.repo.head_hash())
+    }
+
+    fn load(&self, name: &PackageName, version: &Version) -> Result<Option<Vec<u8>>> {
+        let tarball_path = self.repo.workdir()?.join(tarball_path(name, version));
+        if !tarball_path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(tarball_path)?))
+    }
+
+    fn commit_messages(&self) -> Result<Vec<String>> {
+        self.repo.fetch_and_reset()?;
+        self.repo.commit_messages()
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use elba::package::{manifest::Manifest, Name as PackageName};
+use itertools::Itertools;
+use reqwest::Url;
+use semver::Version;
+
+use super::*;
+use crate::config::CONFIG;
+use crate::database;
+use crate::error::{Error, Result};
+
+/// Store tarballs in a local directory, which is served by a web server at the
+/// url prefix
+pub struct LocalStore {
+    dir: PathBuf,
+    url_prefix: String,
+}
+
+impl LocalStore {
+    pub fn new() -> Result<Self> {
+        let dir = CONFIG
+            .store_local_dir
+            .clone()
+            .ok_or(Error::MissingConfig("STORE_LOCAL_DIR"))?;
+        let url_prefix = CONFIG
+            .store_url_prefix
+            .clone()
+            .ok_or(Error::MissingConfig("STORE_URL_PREFIX"))?;
+        fs::create_dir_all(&dir)?;
+        Ok(LocalStore { dir, url_prefix })
+    }
+}
+
+impl StorageBackend for LocalStore {
+    fn put(&self, manifest: &Manifest, tarball: &Path, _: &database::User) -> Result<Url> {
+        let relative_path = tarball_path(&manifest.package.name, &manifest.package.version);
+        let tarball_path = self.dir.join(&relative_path);
+        fs::create_dir_all(tarball_path.parent().unwrap())?;
+        fs::copy(tarball, &tarball_path)?;
+
+        let url = format!(
+            "{}/{}",
+            self.url_prefix.trim_end_matches('/'),
+            relative_path
+                .iter()
+                .map(|part| part.to_string_lossy())
+                .join("/")
+        );
+        Ok(url.parse()?)
+    }
+
+    fn delete(&self, name: &PackageName, version: &Version) -> Result<()> {
+        let tarball_path = self.dir.join(tarball_path(name, version));
+        if tarball_path.exists() {
+            fs::remove_file(tarball_path)?;
+        }
+        Ok(())
+    }
+
+    fn load(&self, name: &PackageName, version: &Version) -> Result<Option<Vec<u8>>> {
+        let tarball_path = self.dir.join(tarball_path(name, version));
+        if !tarball_path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(tarball_path)?))
+    }
+}
@@ -0,0 +1,219 @@
+mod git;
+mod local;
+mod s3;
+
+pub use self::git::GitStore;
+pub use self::local::LocalStore;
+pub use self::s3::S3Store;
+
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use elba::package::{manifest::Manifest, Checksum, ChecksumFmt, Name as PackageName};
+use elba::remote::resolution::DirectRes;
+use failure::bail;
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use reqwest::Url;
+use semver::Version;
+use sha2::{Digest, Sha256};
+
+use super::signing::Signer;
+use super::*;
+use crate::config::{StoreBackend, CONFIG};
+use crate::database;
+use crate::error::{Error, Result};
+
+/// A place to keep package tarballs, which can be downloaded from the returned url
+pub trait StorageBackend: Send + Sync {
+    fn put(&self, manifest: &Manifest, tarball: &Path, publisher: &database::User) -> Result<Url>;
+
+    /// Remove the tarball put before, used to roll back a failed publish
+    fn delete(&self, name: &PackageName, version: &Version) -> Result<()>;
+
+    /// Bring the local view of the store up to date before reading from it
+    fn refresh(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The commit holding the tarballs put so far, if the store is a repository
+    fn commit_hash(&self) -> Option<String> {
+        None
+    }
+
+    /// Read the stored tarball, or `None` if it's missing
+    fn load(&self, name: &PackageName, version: &Version) -> Result<Option<Vec<u8>>>;
+
+    /// Messages recording the history of the store, from the oldest to the newest
+    fn commit_messages(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+pub struct Store {
+    backend: Box<dyn StorageBackend>,
+}
+
+impl Store {
+    /// Create the store, whose commits are signed by the signer if it's kept
+    /// in a repository
+    pub fn new(signer: Option<Arc<Signer>>) -> Result<Self> {
+        let backend: Box<dyn StorageBackend> = match CONFIG.store_backend {
+            StoreBackend::Git => Box::new(GitStore::clone(signer)?),
+            StoreBackend::Local => Box::new(LocalStore::new()?),
+            StoreBackend::S3 => Box::new(S3Store::new()?),
+        };
+        Ok(Store { backend })
+    }
+
+    pub fn upload_package(
+        &self,
+        manifest: &Manifest,
+        tarball: &Path,
+        publisher: &database::User,
+    ) -> Result<DirectRes> {
+        info!(
+            "Uploading package `{} {}`",
+            &manifest.package.name, &manifest.package.version
+        );
+
+        // Check size limit
+        let size = fs::metadata(tarball)?.len();
+        if size > CONFIG.store_max_size {
+            bail!(Error::PackageOversize {
+                size,
+                limit: CONFIG.store_max_size
+            });
+        }
+
+        // Calculate the sha256 checksum
+        let cksum = sha256_file(tarball)?;
+        info!(
+            "Package checksum `{} {}`: {}",
+            &manifest.package.name, &manifest.package.version, &cksum
+        );
+
+        let url = self.backend.put(manifest, tarball, publisher)?;
+
+        // Verify the download from store, and don't leave the tarball behind if
+        // it can't be verified, since the publish fails without recording it
+        info!(
+            "Verifying download of package `{} {}`",
+            &manifest.package.name, &manifest.package.version
+        );
+        if let Err(error) = verify_download(&url, &cksum) {
+            if let Err(delete_error) =
+                self.delete_package(&manifest.package.name, &manifest.package.version)
+            {
+                warn!("Failed to delete unverified tarball: {}", delete_error);
+            }
+            return Err(error);
+        }
+
+        info!(
+            "Uploaded package `{} {}`",
+            &manifest.package.name, &manifest.package.version
+        );
+
+        Ok(DirectRes::Tar {
+            url,
+            cksum: Some(Checksum {
+                fmt: ChecksumFmt::Sha256,
+                hash: cksum,
+            }),
+        })
+    }
+
+    pub fn delete_package(&self, name: &PackageName, version: &Version) -> Result<()> {
+        info!("Deleting package `{} {}` from store", name, version);
+        self.backend.delete(name, version)
+    }
+
+    pub fn refresh(&self) -> Result<()> {
+        self.backend.refresh()
+    }
+
+    pub fn commit_hash(&self) -> Option<String> {
+        self.backend.commit_hash()
+    }
+
+    pub fn package_checksum(
+        &self,
+        name: &PackageName,
+        version: &Version,
+    ) -> Result<Option<String>> {
+        Ok(self
+            .backend
+            .load(name, version)?
+            .map(|tarball| hex::encode(Sha256::digest(&tarball))))
+    }
+
+    /// Read the manifest in the stored tarball, or `None` if the tarball is missing
+    pub fn load_manifest(&self, name: &PackageName, version: &Version) -> Result<Option<Manifest>> {
+        let tarball = match self.backend.load(name, version)? {
+            Some(tarball) => tarball,
+            None => return Ok(None),
+        };
+
+        let mut archive = tar::Archive::new(GzDecoder::new(&tarball[..]));
+        for file in archive.entries()? {
+            let mut file = file?;
+            if file.path()?.file_name() == Some(OsStr::new("elba.toml")) {
+                let mut content = String::new();
+                file.read_to_string(&mut content)?;
+                return Ok(Some(Manifest::from_str(&content)?));
+            }
+        }
+        bail!(Error::ManifestNotFound {
+            package: name.to_string(),
+            version: version.clone(),
+        })
+    }
+
+    pub fn commit_messages(&self) -> Result<Vec<String>> {
+        self.backend.commit_messages()
+    }
+}
+
+/// Path of the tarball relative to the root of store
+fn tarball_path(name: &PackageName, version: &Version) -> PathBuf {
+    Path::new(name.normalized_group())
+        .join(name.normalized_name())
+        .join(tarball_name(name, version))
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut hash = Sha256::new();
+    let mut file = File::open(path)?;
+    io::copy(&mut file, &mut hash)?;
+    Ok(hex::encode(hash.result()))
+}
+
+fn verify_download(url: &Url, cksum: &str) -> Result<()> {
+    let download_cksum = sha256_download(url)?;
+    if download_cksum != cksum {
+        bail!(Error::DownloadVerification {
+            local_cksum: cksum.to_owned(),
+            download_cksum
+        })
+    }
+    Ok(())
+}
+
+fn sha256_download(url: &Url) -> Result<String> {
+    if url.scheme() == "file" {
+        let path = url
+            .to_file_path()
+            .map_err(|_| failure::format_err!("invalid file url `{}`", url))?;
+        return sha256_file(&path);
+    }
+
+    let mut hash = Sha256::new();
+    let mut download = reqwest::blocking::get(url.clone())?.error_for_status()?;
+    io::copy(&mut download, &mut hash)?;
+    Ok(hex::encode(hash.result()))
+}
@@ -0,0 +1,274 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::Utc;
+use elba::package::{manifest::Manifest, Name as PackageName};
+use hmac::{Hmac, Mac};
+use itertools::Itertools;
+use reqwest::{
+    blocking::{Client, Response},
+    Method, StatusCode, Url,
+};
+use semver::Version;
+use sha2::{Digest, Sha256};
+
+use super::*;
+use crate::config::CONFIG;
+use crate::database;
+use crate::error::{Error, Result};
+
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Store tarballs in an S3 compatible object store, such as AWS S3 or MinIO
+///
+/// Objects are addressed in path style, i.e. `{endpoint}/{bucket}/{key}`, and
+/// the bucket is expected to allow anonymous downloads.
+pub struct S3Store {
+    client: Client,
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    url_prefix: Option<String>,
+}
+
+impl S3Store {
+    pub fn new() -> Result<Self> {
+        Ok(S3Store {
+            client: Client::new(),
+            endpoint: CONFIG
+                .s3_endpoint
+                .clone()
+                .ok_or(Error::MissingConfig("S3_ENDPOINT"))?,
+            bucket: CONFIG
+                .s3_bucket
+                .clone()
+                .ok_or(Error::MissingConfig("S3_BUCKET"))?,
+            region: CONFIG
+                .s3_region
+                .clone()
+                .unwrap_or_else(|| DEFAULT_REGION.to_owned()),
+            access_key: CONFIG
+                .s3_access_key
+                .clone()
+                .ok_or(Error::MissingConfig("S3_ACCESS_KEY"))?,
+            secret_key: CONFIG
+                .s3_secret_key
+                .clone()
+                .ok_or(Error::MissingConfig("S3_SECRET_KEY"))?,
+            url_prefix: CONFIG.store_url_prefix.clone(),
+        })
+    }
+}
+
+impl S3Store {
+    fn object_key(name: &PackageName, version: &Version) -> String {
+        tarball_path(name, version)
+            .iter()
+            .map(|part| uri_encode(&part.to_string_lossy()))
+            .join("/")
+    }
+
+    fn object_url(&self, key: &str) -> Result<Url> {
+        Ok(format!(
+            "{}/{}/{}",
+            self.endpoint.trim_end_matches('/'),
+            uri_encode(&self.bucket),
+            key
+        )
+        .parse()?)
+    }
+
+    /// Send a request signed with AWS signature version 4
+    fn send(&self, method: Method, url: &Url, body: Vec<u8>) -> Result<Response> {
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            url.path(),
+            host,
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signature = hex::encode(hmac_sha256(
+            &signing_key(&self.secret_key, &date, &self.region, "s3"),
+            string_to_sign.as_bytes(),
+        ));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        Ok(self
+            .client
+            .request(method, url.clone())
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+            .body(body)
+            .send()?)
+    }
+}
+
+impl StorageBackend for S3Store {
+    fn put(&self, manifest: &Manifest, tarball: &Path, _: &database::User) -> Result<Url> {
+        let key = S3Store::object_key(&manifest.package.name, &manifest.package.version);
+        let object_url = self.object_url(&key)?;
+        self.send(Method::PUT, &object_url, fs::read(tarball)?)?
+            .error_for_status()?;
+
+        match &self.url_prefix {
+            Some(url_prefix) => {
+                Ok(format!("{}/{}", url_prefix.trim_end_matches('/'), key).parse()?)
+            }
+            None => Ok(object_url),
+        }
+    }
+
+    fn delete(&self, name: &PackageName, version: &Version) -> Result<()> {
+        let object_url = self.object_url(&S3Store::object_key(name, version))?;
+        self.send(Method::DELETE, &object_url, Vec::new())?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn load(&self, name: &PackageName, version: &Version) -> Result<Option<Vec<u8>>> {
+        let object_url = self.object_url(&S3Store::object_key(name, version))?;
+        let resp = self.send(Method::GET, &object_url, Vec::new())?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(resp.error_for_status()?.bytes()?.to_vec()))
+    }
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+/// Percent-encode everything except the unreserved characters, as required by
+/// the canonical uri of signature version 4
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_signing_key() {
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(
+            uri_encode("group_pkg_1.0.0+build.tar.gz"),
+            "group_pkg_1.0.0%2Bbuild.tar.gz"
+        );
+        assert_eq!(uri_encode("a b~"), "a%20b~");
+    }
+
+    /// Publish and roll back against the object store at `S3_TEST_ENDPOINT`,
+    /// e.g. a local MinIO, which is skipped if it's not set
+    ///
+    /// The bucket `S3_TEST_BUCKET` must exist and allow anonymous downloads,
+    /// e.g. by `mc anonymous set download`.
+    #[test]
+    fn test_upload_and_rollback() {
+        let endpoint = match env::var("S3_TEST_ENDPOINT") {
+            Ok(endpoint) => endpoint,
+            Err(_) => return,
+        };
+        let _lock = testing::lock();
+        let store = Store {
+            backend: Box::new(S3Store {
+                client: Client::new(),
+                endpoint,
+                bucket: env::var("S3_TEST_BUCKET").unwrap_or_else(|_| "elba".to_owned()),
+                region: DEFAULT_REGION.to_owned(),
+                access_key: env::var("S3_TEST_ACCESS_KEY")
+                    .unwrap_or_else(|_| "minioadmin".to_owned()),
+                secret_key: env::var("S3_TEST_SECRET_KEY")
+                    .unwrap_or_else(|_| "minioadmin".to_owned()),
+                url_prefix: None,
+            }),
+        };
+        let dir = TempDir::new("elba-bot-s3").unwrap();
+        testing::init_package_repo(dir.path(), "s3/pkg", "0.1.0");
+        let (tarball, manifest) = elba::cli::index::package(dir.path()).unwrap();
+        let name = &manifest.package.name;
+        let version = &manifest.package.version;
+        let publisher = database::User {
+            id: 1,
+            name: "alice".to_owned(),
+        };
+
+        // The tarball is downloaded to be verified on upload
+        let location = store
+            .upload_package(&manifest, &tarball, &publisher)
+            .unwrap();
+        match location {
+            DirectRes::Tar { url, .. } => assert!(url.path().ends_with("/s3_pkg_0.1.0.tar.gz")),
+            location => panic!("unexpected location {:?}", location),
+        }
+        store.refresh().unwrap();
+        assert_eq!(
+            store.backend.load(name, version).unwrap(),
+            Some(fs::read(&tarball).unwrap())
+        );
+
+        store.delete_package(name, version).unwrap();
+        assert_eq!(store.backend.load(name, version).unwrap(), None);
+    }
+}